@@ -6,9 +6,28 @@ use bevy::prelude::*;
 use bevy_rapier3d::physics::{RapierConfiguration, RapierPhysicsPlugin};
 use debug_fly_controller::DebugFlyControllerPlugin;
 use player_controller::PlayerControllerPlugin;
-use voxel_terrain::generator::VoxelTerrainGeneratorPlugin;
+use voxel_terrain::{
+    config::TerrainConfig,
+    generator::VoxelTerrainGeneratorPlugin,
+    worlds::{WorldManager, DEFAULT_SAVES_DIR, DEFAULT_WORLD_NAME},
+};
+
+const TERRAIN_CONFIG_PATH: &str = "assets/config/terrain.ron";
 
 fn main() {
+    let args = Args::parse().unwrap_or_else(|err| panic!("{}", err));
+    let worlds = WorldManager::new(&args.saves_dir);
+    if args.list_worlds {
+        print_worlds(&worlds);
+        return;
+    }
+    let world = worlds
+        .world(&args.world)
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let terrain_config = TerrainConfig::load_or_default(TERRAIN_CONFIG_PATH)
+        .unwrap_or_else(|err| panic!("{}: {}", TERRAIN_CONFIG_PATH, err));
+
     App::build()
         .add_resource(WindowDescriptor {
             title: "Bevy Blocks".to_string(),
@@ -27,12 +46,63 @@ fn main() {
             time_dependent_number_of_timesteps: true,
             ..Default::default()
         })
+        .add_resource(terrain_config)
+        .add_resource(world)
         //.add_plugin(DebugFlyControllerPlugin)
         .add_plugin(PlayerControllerPlugin)
         .add_plugin(VoxelTerrainGeneratorPlugin)
         .run();
 }
 
+/// Command line options. `--world <name>` picks the world to play, creating it if needed,
+/// `--saves <dir>` sets the directory worlds are kept in, and `--list-worlds` prints the worlds
+/// there and exits.
+struct Args {
+    world: String,
+    saves_dir: String,
+    list_worlds: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Self {
+            world: DEFAULT_WORLD_NAME.to_string(),
+            saves_dir: DEFAULT_SAVES_DIR.to_string(),
+            list_worlds: false,
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--world" => args.world = iter.next().ok_or("--world needs a world name")?,
+                "--saves" => args.saves_dir = iter.next().ok_or("--saves needs a directory")?,
+                "--list-worlds" => args.list_worlds = true,
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+fn print_worlds(worlds: &WorldManager) {
+    let summaries = worlds
+        .list()
+        .unwrap_or_else(|err| panic!("{}: {}", worlds.root().display(), err));
+    if summaries.is_empty() {
+        println!("No worlds in {}", worlds.root().display());
+    }
+    for summary in summaries {
+        match summary.level {
+            Some(level) => println!(
+                "{}\tseed {}\t{} generator",
+                summary.name, level.seed, level.generator
+            ),
+            None => println!("{}\tunreadable level.dat", summary.name),
+        }
+    }
+}
+
 /// set up a simple 3D scene
 fn setup(
     commands: &mut Commands,
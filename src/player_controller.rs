@@ -1,5 +1,14 @@
-use crate::voxel_terrain::{constants::{CHUNK_SIZE, MAX_CHUNK_HEIGHT}, generator::GenerateAtTag};
+use crate::voxel_terrain::{
+    blocks::BlockRegistryResource,
+    config::TerrainConfig,
+    edit::VoxelWorld,
+    generator::{GenerateAtTag, GeneratedVoxelResource, Voxel},
+    level::PlayerState,
+    raycast::{raycast, TargetedBlock},
+    worlds::ActiveWorld,
+};
 use bevy::{prelude::*, render::camera::PerspectiveProjection};
+use building_blocks::core::prelude::*;
 use bevy_prototype_character_controller::{
     controller::{BodyTag, CameraTag, CharacterController, HeadTag, YawTag},
     look::{LookDirection, LookEntity},
@@ -16,7 +25,12 @@ impl Plugin for PlayerControllerPlugin {
     fn build(&self, builder: &mut AppBuilder) {
         builder
             .init_resource::<CharacterSettings>()
+            .init_resource::<TargetedBlock>()
+            .init_resource::<SelectedBlock>()
             .add_startup_system(setup_player_system.system())
+            .add_system(update_targeted_block_system.system())
+            .add_system(edit_block_system.system())
+            .add_system(undo_redo_system.system())
             .add_plugin(RapierDynamicForceCharacterControllerPlugin);
     }
 }
@@ -27,6 +41,17 @@ pub struct CharacterSettings {
     pub head_yaw: f32,
     pub follow_offset: Vec3,
     pub focal_point: Vec3,
+    /// How far away the player can target blocks, in voxels.
+    pub reach: f32,
+}
+
+/// The voxel type placed with the right mouse button.
+pub struct SelectedBlock(pub Voxel);
+
+impl Default for SelectedBlock {
+    fn default() -> Self {
+        Self(Voxel::STONE)
+    }
 }
 
 impl Default for CharacterSettings {
@@ -37,24 +62,40 @@ impl Default for CharacterSettings {
             head_yaw: 0.0,
             focal_point: -Vec3::unit_z(), // Relative to head
             follow_offset: Vec3::zero(),  // Relative to head
+            reach: 8.0,
         }
     }
 }
 
-fn setup_player_system(commands: &mut Commands, character_settings: Res<CharacterSettings>) {
-    let y_spawn_offset = (CHUNK_SIZE * MAX_CHUNK_HEIGHT / 2)  as f32;
+fn setup_player_system(
+    commands: &mut Commands,
+    character_settings: Res<CharacterSettings>,
+    terrain_config: Res<TerrainConfig>,
+    world: Res<ActiveWorld>,
+) {
+    let y_spawn_offset = terrain_config.spawn_height as f32;
     let box_y = 1.0;
+    let default_spawn = Vec3::new(
+        0.0,
+        0.5 * (box_y + character_settings.scale.y) + y_spawn_offset,
+        0.0,
+    );
+    // Pick up where the player left off in a saved world.
+    let spawn = match PlayerState::load(&world.dir) {
+        Ok(Some(state)) => Vec3::from(state.position),
+        Ok(None) => default_spawn,
+        Err(err) => {
+            warn!("Failed to load player state, using the default spawn: {}", err);
+            default_spawn
+        }
+    };
     let body = commands
         .spawn((
             GlobalTransform::identity(),
             Transform::identity(),
             CharacterController::default(),
             RigidBodyBuilder::new_dynamic()
-                .translation(
-                    0.0,
-                    0.5 * (box_y + character_settings.scale.y) + y_spawn_offset,
-                    0.0,
-                )
+                .translation(spawn.x, spawn.y, spawn.z)
                 .principal_angular_inertia(
                     bevy_rapier3d::rapier::na::Vector3::zeros(),
                     bevy_rapier3d::rapier::na::Vector3::repeat(false),
@@ -123,3 +164,87 @@ fn setup_player_system(commands: &mut Commands, character_settings: Res<Characte
         .push_children(yaw, &[head])
         .push_children(head, &[camera]);
 }
+
+/// Casts a ray from the camera along its look direction and stores the first block it hits.
+/// Only solid blocks count, so blocks under water can be targeted.
+fn update_targeted_block_system(
+    character_settings: Res<CharacterSettings>,
+    voxels: Res<GeneratedVoxelResource>,
+    blocks: Res<BlockRegistryResource>,
+    mut targeted: ResMut<TargetedBlock>,
+    query: Query<(&GlobalTransform, &LookDirection), With<CameraTag>>,
+) {
+    targeted.0 = query.iter().next().and_then(|(transform, look)| {
+        raycast(
+            &voxels.map,
+            transform.translation,
+            look.forward,
+            character_settings.reach,
+            |voxel| blocks.0.is_solid(voxel),
+        )
+    });
+}
+
+/// Breaks the targeted block on left click, and places the selected block against the targeted
+/// face on right click. Blocks can replace blocks that aren't solid, and solid blocks aren't placed
+/// where they would overlap the player.
+fn edit_block_system(
+    mouse: Res<Input<MouseButton>>,
+    character_settings: Res<CharacterSettings>,
+    blocks: Res<BlockRegistryResource>,
+    targeted: Res<TargetedBlock>,
+    selected: Res<SelectedBlock>,
+    mut world: VoxelWorld,
+    query: Query<&GlobalTransform, With<BodyTag>>,
+) {
+    let hit = match targeted.0 {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    if mouse.just_pressed(MouseButton::Left) {
+        world.set(hit.position, Voxel::AIR);
+    } else if mouse.just_pressed(MouseButton::Right) {
+        let p: Point3i = hit.adjacent();
+        let replaceable = world
+            .get(p)
+            .map_or(false, |voxel| !blocks.0.is_solid(voxel));
+        if !replaceable {
+            return;
+        }
+        // Bounds of the body's capsule collider.
+        let scale = character_settings.scale;
+        let radius = 0.5 * scale.x.max(scale.z);
+        let half_extents = Vec3::new(radius, 0.5 * scale.y + radius, radius);
+        let blocked = blocks.0.is_solid(selected.0) && query.iter().any(|transform| {
+            let min = transform.translation - half_extents;
+            let max = transform.translation + half_extents;
+            (min.x < (p.x() + 1) as f32 && max.x > p.x() as f32)
+                && (min.y < (p.y() + 1) as f32 && max.y > p.y() as f32)
+                && (min.z < (p.z() + 1) as f32 && max.z > p.z() as f32)
+        });
+        if !blocked {
+            world.set(p, selected.0);
+        }
+    }
+}
+
+/// Ctrl+Z undoes the last world edit, and Ctrl+Y or Ctrl+Shift+Z redoes it.
+fn undo_redo_system(keys: Res<Input<KeyCode>>, mut world: VoxelWorld) {
+    let ctrl = keys.pressed(KeyCode::LControl) || keys.pressed(KeyCode::RControl);
+    let shift = keys.pressed(KeyCode::LShift) || keys.pressed(KeyCode::RShift);
+    if !ctrl {
+        return;
+    }
+
+    let result = if keys.just_pressed(KeyCode::Y) || (shift && keys.just_pressed(KeyCode::Z)) {
+        world.redo()
+    } else if keys.just_pressed(KeyCode::Z) {
+        world.undo()
+    } else {
+        return;
+    };
+    if let Err(err) = result {
+        info!("Can't undo or redo: {}", err);
+    }
+}
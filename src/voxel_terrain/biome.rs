@@ -0,0 +1,210 @@
+use super::generator::Voxel;
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Biome {
+    Ocean,
+    Plains,
+    Desert,
+    Mountains,
+    Tundra,
+}
+
+/// A block type that covers the top `depth` voxels below the surface, or what is left of them
+/// after the layers above it.
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceLayer {
+    pub voxel: Voxel,
+    pub depth: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BiomeParams {
+    /// Where the biome sits in climate space. Both axes go from -1 to 1.
+    pub temperature: f64,
+    pub humidity: f64,
+    /// Added to the sea level to get the base terrain height.
+    pub height_offset: f64,
+    /// Multiplies the terrain noise amplitude.
+    pub height_scale: f64,
+    /// Layers from the surface down. Anything deeper is stone.
+    pub surface: &'static [SurfaceLayer],
+}
+
+const OCEAN_SURFACE: &[SurfaceLayer] = &[
+    SurfaceLayer {
+        voxel: Voxel::SAND,
+        depth: 3,
+    },
+    SurfaceLayer {
+        voxel: Voxel::GRAVEL,
+        depth: 2,
+    },
+];
+const PLAINS_SURFACE: &[SurfaceLayer] = &[
+    SurfaceLayer {
+        voxel: Voxel::GRASS,
+        depth: 1,
+    },
+    SurfaceLayer {
+        voxel: Voxel::DIRT,
+        depth: 3,
+    },
+];
+const DESERT_SURFACE: &[SurfaceLayer] = &[SurfaceLayer {
+    voxel: Voxel::SAND,
+    depth: 5,
+}];
+const MOUNTAINS_SURFACE: &[SurfaceLayer] = &[SurfaceLayer {
+    voxel: Voxel::GRAVEL,
+    depth: 1,
+}];
+const TUNDRA_SURFACE: &[SurfaceLayer] = &[
+    SurfaceLayer {
+        voxel: Voxel::SNOW,
+        depth: 1,
+    },
+    SurfaceLayer {
+        voxel: Voxel::DIRT,
+        depth: 2,
+    },
+];
+
+impl Biome {
+    pub const ALL: [Biome; 5] = [
+        Biome::Ocean,
+        Biome::Plains,
+        Biome::Desert,
+        Biome::Mountains,
+        Biome::Tundra,
+    ];
+
+    pub fn params(self) -> BiomeParams {
+        match self {
+            Biome::Ocean => BiomeParams {
+                temperature: 0.0,
+                humidity: 0.9,
+                height_offset: -20.0,
+                height_scale: 0.2,
+                surface: OCEAN_SURFACE,
+            },
+            Biome::Plains => BiomeParams {
+                temperature: 0.2,
+                humidity: 0.2,
+                height_offset: 4.0,
+                height_scale: 0.25,
+                surface: PLAINS_SURFACE,
+            },
+            Biome::Desert => BiomeParams {
+                temperature: 0.8,
+                humidity: -0.6,
+                height_offset: 3.0,
+                height_scale: 0.2,
+                surface: DESERT_SURFACE,
+            },
+            Biome::Mountains => BiomeParams {
+                temperature: -0.2,
+                humidity: -0.3,
+                height_offset: 20.0,
+                height_scale: 1.2,
+                surface: MOUNTAINS_SURFACE,
+            },
+            Biome::Tundra => BiomeParams {
+                temperature: -0.8,
+                humidity: 0.1,
+                height_offset: 8.0,
+                height_scale: 0.5,
+                surface: TUNDRA_SURFACE,
+            },
+        }
+    }
+}
+
+/// What the generator needs to know about one column of terrain.
+#[derive(Clone, Copy, Debug)]
+pub struct ColumnSample {
+    /// The biome with the most weight in this column. Picks the surface layers.
+    pub biome: Biome,
+    /// Height offset and scale blended over all biomes, so borders are smooth.
+    pub height_offset: f64,
+    pub height_scale: f64,
+}
+
+/// Temperature and humidity noise maps that pick a biome for each column.
+pub struct BiomeMap {
+    temperature: Fbm,
+    humidity: Fbm,
+}
+
+/// How quickly a biome's weight falls off with distance in climate space. Smaller values give
+/// sharper borders.
+const BLEND_WIDTH: f64 = 0.05;
+
+impl BiomeMap {
+    pub fn new(seed: u32, frequency: f64) -> Self {
+        Self {
+            temperature: Fbm::new()
+                .set_seed(seed.wrapping_add(1))
+                .set_frequency(frequency)
+                .set_octaves(3),
+            humidity: Fbm::new()
+                .set_seed(seed.wrapping_add(2))
+                .set_frequency(frequency)
+                .set_octaves(3),
+        }
+    }
+
+    pub fn sample(&self, x: i32, z: i32) -> ColumnSample {
+        let point = [x as f64, z as f64];
+        let temperature = self.temperature.get(point).max(-1.0).min(1.0);
+        let humidity = self.humidity.get(point).max(-1.0).min(1.0);
+
+        let mut biome = Biome::Plains;
+        let mut best_weight = 0.0;
+        let mut total_weight = 0.0;
+        let mut height_offset = 0.0;
+        let mut height_scale = 0.0;
+        for candidate in Biome::ALL.iter() {
+            let params = candidate.params();
+            let dt = temperature - params.temperature;
+            let dh = humidity - params.humidity;
+            let weight = (-(dt * dt + dh * dh) / BLEND_WIDTH).exp();
+            if weight > best_weight {
+                best_weight = weight;
+                biome = *candidate;
+            }
+            total_weight += weight;
+            height_offset += weight * params.height_offset;
+            height_scale += weight * params.height_scale;
+        }
+
+        ColumnSample {
+            biome,
+            height_offset: height_offset / total_weight,
+            height_scale: height_scale / total_weight,
+        }
+    }
+}
+
+/// The voxel at `depth` below the surface of a column in `biome`, where the surface itself is
+/// depth 0.
+pub fn surface_voxel(biome: Biome, depth: i32) -> Voxel {
+    let mut layer_bottom = 0;
+    for layer in biome.params().surface.iter() {
+        layer_bottom += layer.depth;
+        if depth < layer_bottom {
+            return layer.voxel;
+        }
+    }
+
+    Voxel::STONE
+}
+
+/// The deepest any biome's surface layers reach.
+pub fn max_surface_depth() -> i32 {
+    Biome::ALL
+        .iter()
+        .map(|biome| biome.params().surface.iter().map(|layer| layer.depth).sum::<i32>())
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,294 @@
+use super::generator::Voxel;
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+use std::{fmt, sync::Arc};
+
+/// A side of a voxel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    /// +Y
+    Top,
+    /// -Y
+    Bottom,
+    /// -Z
+    North,
+    /// +Z
+    South,
+    /// +X
+    East,
+    /// -X
+    West,
+}
+
+/// Texture array layers for each face of a block.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum BlockTextures {
+    All(u32),
+    TopSideBottom(u32, u32, u32),
+    Faces {
+        top: u32,
+        bottom: u32,
+        north: u32,
+        south: u32,
+        east: u32,
+        west: u32,
+    },
+}
+
+impl BlockTextures {
+    pub fn layer(&self, face: Face) -> u32 {
+        match *self {
+            Self::All(layer) => layer,
+            Self::TopSideBottom(top, side, bottom) => match face {
+                Face::Top => top,
+                Face::Bottom => bottom,
+                _ => side,
+            },
+            Self::Faces {
+                top,
+                bottom,
+                north,
+                south,
+                east,
+                west,
+            } => match face {
+                Face::Top => top,
+                Face::Bottom => bottom,
+                Face::North => north,
+                Face::South => south,
+                Face::East => east,
+                Face::West => west,
+            },
+        }
+    }
+
+    fn max_layer(&self) -> u32 {
+        [
+            Face::Top,
+            Face::Bottom,
+            Face::North,
+            Face::South,
+            Face::East,
+            Face::West,
+        ]
+        .iter()
+        .map(|face| self.layer(*face))
+        .max()
+        .unwrap_or(0)
+    }
+}
+
+/// Sound asset paths played when a block is interacted with.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BlockSounds {
+    pub dig: Option<String>,
+    pub place: Option<String>,
+    pub step: Option<String>,
+}
+
+/// One block type.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BlockDef {
+    pub name: String,
+    /// The `Voxel` value of the block. 0 is always air and can't be defined.
+    pub id: u8,
+    pub textures: BlockTextures,
+    /// Whether the block gets a collider.
+    pub solid: bool,
+    /// Whether the block can be seen through. Transparent blocks go in the chunk's translucent
+    /// mesh, and faces of opaque blocks behind them are kept.
+    pub transparent: bool,
+    /// Light given off by the block, from 0 to 15.
+    pub light: u8,
+    /// How long the block takes to break, in seconds.
+    pub hardness: f32,
+    pub sounds: BlockSounds,
+}
+
+impl Default for BlockDef {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            id: 0,
+            textures: BlockTextures::All(0),
+            solid: true,
+            transparent: false,
+            light: 0,
+            hardness: 1.0,
+            sounds: BlockSounds::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockRegistryFile {
+    blocks: Vec<BlockDef>,
+}
+
+/// Every block type, looked up by voxel value. Loaded from a `.ron` asset, so editing the file
+/// while the game runs updates the blocks and remeshes the world.
+///
+/// Voxels with no definition are drawn with texture layer 0 and treated as solid and opaque.
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "3c1b8a6e-5d0f-4b7e-9a43-2f6e1d7c8b90"]
+pub struct BlockRegistry {
+    blocks: Vec<Option<BlockDef>>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        Self {
+            blocks: vec![None; 256],
+        }
+    }
+}
+
+impl BlockRegistry {
+    pub fn new(defs: Vec<BlockDef>) -> Result<Self, BlockRegistryError> {
+        let mut registry = Self::default();
+        for def in defs {
+            if def.id == 0 {
+                return Err(BlockRegistryError::AirRedefined(def.name));
+            }
+            if def.light > 15 {
+                return Err(BlockRegistryError::LightOutOfRange(def.name, def.light));
+            }
+            if def.hardness < 0.0 {
+                return Err(BlockRegistryError::HardnessNegative(def.name, def.hardness));
+            }
+            if registry.by_name(&def.name).is_some() {
+                return Err(BlockRegistryError::DuplicateName(def.name));
+            }
+            let slot = &mut registry.blocks[def.id as usize];
+            if slot.is_some() {
+                return Err(BlockRegistryError::DuplicateId(def.id));
+            }
+            *slot = Some(def);
+        }
+
+        Ok(registry)
+    }
+
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, BlockRegistryError> {
+        let file: BlockRegistryFile =
+            ron::de::from_bytes(bytes).map_err(BlockRegistryError::Ron)?;
+        Self::new(file.blocks)
+    }
+
+    /// The blocks the game shipped with, built into the binary. Used when the asset can't be
+    /// loaded.
+    pub fn builtin() -> Self {
+        Self::from_ron(include_bytes!("../../assets/config/default.blocks"))
+            .expect("Built-in block registry is invalid")
+    }
+
+    pub fn get(&self, voxel: Voxel) -> Option<&BlockDef> {
+        self.blocks[voxel.0 as usize].as_ref()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&BlockDef> {
+        self.blocks.iter().flatten().find(|def| def.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockDef> {
+        self.blocks.iter().flatten()
+    }
+
+    pub fn is_solid(&self, voxel: Voxel) -> bool {
+        voxel != Voxel::AIR && self.get(voxel).map_or(true, |def| def.solid)
+    }
+
+    pub fn is_transparent(&self, voxel: Voxel) -> bool {
+        self.get(voxel).map_or(false, |def| def.transparent)
+    }
+
+    /// The texture array layer for `face` of `voxel`.
+    pub fn texture_layer(&self, voxel: Voxel, face: Face) -> u32 {
+        self.get(voxel).map_or(0, |def| def.textures.layer(face))
+    }
+
+    /// Voxel values of every transparent block.
+    pub fn transparent_voxels(&self) -> Vec<Voxel> {
+        self.iter()
+            .filter(|def| def.transparent)
+            .map(|def| Voxel(def.id))
+            .collect()
+    }
+
+    /// The highest texture layer any block uses.
+    pub fn max_texture_layer(&self) -> u32 {
+        self.iter()
+            .map(|def| def.textures.max_layer())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockRegistryError {
+    Ron(ron::Error),
+    AirRedefined(String),
+    DuplicateId(u8),
+    DuplicateName(String),
+    LightOutOfRange(String, u8),
+    HardnessNegative(String, f32),
+}
+
+impl fmt::Display for BlockRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ron(err) => write!(f, "failed to parse block registry: {}", err),
+            Self::AirRedefined(name) => {
+                write!(f, "block {:?} uses id 0, which is reserved for air", name)
+            }
+            Self::DuplicateId(id) => write!(f, "more than one block has id {}", id),
+            Self::DuplicateName(name) => write!(f, "more than one block is named {:?}", name),
+            Self::LightOutOfRange(name, light) => write!(
+                f,
+                "block {:?} has light {}, which must be at most 15",
+                name, light
+            ),
+            Self::HardnessNegative(name, hardness) => write!(
+                f,
+                "block {:?} has hardness {}, which must not be negative",
+                name, hardness
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockRegistryError {}
+
+/// The block registry in use. Meshing tasks share it, so a reload never changes the blocks under
+/// a mesh that is being built.
+#[derive(Default)]
+pub struct BlockRegistryResource(pub Arc<BlockRegistry>);
+
+/// Loads `.blocks` files, which are RON. Other RON assets, like the terrain config, are left to
+/// their own loaders.
+#[derive(Default)]
+pub struct BlockRegistryLoader;
+
+impl AssetLoader for BlockRegistryLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let registry = BlockRegistry::from_ron(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(registry));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["blocks"]
+    }
+}
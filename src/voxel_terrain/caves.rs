@@ -0,0 +1,67 @@
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, Seedable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaveConfig {
+    pub enabled: bool,
+    /// Frequency of the large open "cheese" caverns.
+    pub cheese_frequency: f64,
+    /// Noise value above which a voxel is part of a cavern. Higher values give fewer caverns.
+    pub cheese_threshold: f64,
+    /// Frequency of the long "worm" tunnels.
+    pub worm_frequency: f64,
+    /// Half-thickness of the worm tunnels, in noise units.
+    pub worm_radius: f64,
+}
+
+impl Default for CaveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cheese_frequency: 0.02,
+            cheese_threshold: 0.55,
+            worm_frequency: 0.015,
+            worm_radius: 0.06,
+        }
+    }
+}
+
+/// Carves caves out of solid terrain. Everything is sampled from world-space 3D noise, so a cave
+/// that crosses a chunk border lines up no matter which chunk is generated first.
+pub struct CaveCarver {
+    cheese: Fbm,
+    worm_a: Perlin,
+    worm_b: Perlin,
+    config: CaveConfig,
+}
+
+impl CaveCarver {
+    pub fn new(seed: u32, config: &CaveConfig) -> Self {
+        Self {
+            cheese: Fbm::new()
+                .set_seed(seed.wrapping_add(10))
+                .set_frequency(config.cheese_frequency)
+                .set_octaves(2),
+            worm_a: Perlin::new().set_seed(seed.wrapping_add(11)),
+            worm_b: Perlin::new().set_seed(seed.wrapping_add(12)),
+            config: config.clone(),
+        }
+    }
+
+    pub fn is_cave(&self, x: i32, y: i32, z: i32) -> bool {
+        let (x, y, z) = (x as f64, y as f64, z as f64);
+
+        // Squash caverns vertically so they are wider than they are tall.
+        if self.cheese.get([x, y * 2.0, z]) > self.config.cheese_threshold {
+            return true;
+        }
+
+        // A worm tunnel is where the zero surfaces of two independent noise fields intersect.
+        let f = self.config.worm_frequency;
+        let p = [x * f, y * f, z * f];
+        let r = self.config.worm_radius;
+
+        self.worm_a.get(p).abs() < r && self.worm_b.get(p).abs() < r
+    }
+}
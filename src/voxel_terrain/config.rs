@@ -0,0 +1,318 @@
+use super::{
+    caves::CaveConfig,
+    generator::Voxel,
+    ores::{default_ores, OreConfig},
+    save_load::SaveMode,
+    structures::TreeConfig,
+    water::WaterConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, path::Path};
+
+/// Which built-in `TerrainGenerator` the plugin registers.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GeneratorKind {
+    /// 2D heightmap. Fast, but cannot form overhangs.
+    Heightmap,
+    /// 3D density function on top of the heightmap, allowing overhangs and arches.
+    Density,
+}
+
+/// Settings for the voxel terrain. `VoxelTerrainGeneratorPlugin` reads this resource when it is
+/// built, so it must be added to the app before the plugin.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainConfig {
+    /// Width, depth and height of a chunk, in voxels.
+    pub chunk_size: i32,
+    pub sea_level: f64,
+    pub terrain_y_scale: f64,
+    /// Radius around the player in which chunks are generated, in voxels.
+    pub view_distance: i32,
+    /// How far above and below the player chunks are generated, in voxels.
+    pub vertical_view_distance: i32,
+    /// How far past the view distance a chunk must be before its voxels are unloaded, in voxels.
+    pub unload_margin: i32,
+    /// Height the player spawns at.
+    pub spawn_height: i32,
+    /// Number of layers in the terrain texture, not counting layer 0.
+    pub num_texture_layers: u32,
+    pub seed: u32,
+    pub noise_frequency: f64,
+    pub noise_octaves: usize,
+    /// Frequency of the temperature and humidity maps that pick biomes.
+    pub biome_frequency: f64,
+    pub generator: GeneratorKind,
+    /// Frequency of the 3D noise added by the density generator.
+    pub density_frequency: f64,
+    /// How many voxels it takes for the density generator's base density to change by 1. Larger
+    /// values let the 3D noise push terrain further from the heightmap.
+    pub density_squash: f64,
+    pub caves: CaveConfig,
+    /// Ores placed after the base terrain, in order.
+    pub ores: Vec<OreConfig>,
+    pub trees: TreeConfig,
+    pub water: WaterConfig,
+    /// Most chunks that can be generating in the background at once.
+    pub max_generation_tasks: usize,
+    /// Most chunks that can be meshing in the background at once.
+    pub max_meshing_tasks: usize,
+    /// Most chunk generations started per frame.
+    pub generations_per_frame: usize,
+    /// Most finished chunk meshes turned into entities per frame.
+    pub mesh_uploads_per_frame: usize,
+    /// How much to favour chunks in front of the player when picking what to load next, from 0
+    /// (distance only) up to, but not including, 1.
+    pub view_direction_bias: f32,
+    /// How modified chunks are written to disk.
+    pub save_mode: SaveMode,
+    /// Seconds between autosaves of modified chunks and the player's position. 0 turns autosave
+    /// off; the world is still saved on exit.
+    pub autosave_interval: f32,
+    /// Most world edits that can be undone. 0 turns the edit history off.
+    pub edit_history_depth: usize,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 16,
+            sea_level: 50.0,
+            terrain_y_scale: 1.0,
+            view_distance: 192,
+            vertical_view_distance: 64,
+            unload_margin: 32,
+            spawn_height: 128,
+            num_texture_layers: 15,
+            seed: 1234,
+            noise_frequency: 0.008,
+            noise_octaves: 5,
+            biome_frequency: 0.002,
+            generator: GeneratorKind::Heightmap,
+            density_frequency: 0.02,
+            density_squash: 12.0,
+            caves: CaveConfig::default(),
+            ores: default_ores(),
+            trees: TreeConfig::default(),
+            water: WaterConfig::default(),
+            max_generation_tasks: 16,
+            max_meshing_tasks: 16,
+            generations_per_frame: 4,
+            mesh_uploads_per_frame: 4,
+            view_direction_bias: 0.5,
+            save_mode: SaveMode::Full,
+            autosave_interval: 60.0,
+            edit_history_depth: 100,
+        }
+    }
+}
+
+impl TerrainConfig {
+    /// Loads and validates a config from a `.ron` or `.toml` file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, TerrainConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(TerrainConfigError::Io)?;
+        let config: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ron") => ron::de::from_str(&contents).map_err(TerrainConfigError::Ron)?,
+            Some("toml") => toml::from_str(&contents).map_err(TerrainConfigError::Toml)?,
+            _ => return Err(TerrainConfigError::UnsupportedFormat(path.display().to_string())),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like `load`, but falls back to the default config when the file does not exist.
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<Self, TerrainConfigError> {
+        if path.as_ref().exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Takes everything that shapes the terrain from `world`, the config a saved world was
+    /// created with, and keeps the settings that only affect this session, like view distance.
+    pub fn with_world_settings(&self, world: &TerrainConfig) -> Self {
+        Self {
+            chunk_size: world.chunk_size,
+            sea_level: world.sea_level,
+            terrain_y_scale: world.terrain_y_scale,
+            seed: world.seed,
+            noise_frequency: world.noise_frequency,
+            noise_octaves: world.noise_octaves,
+            biome_frequency: world.biome_frequency,
+            generator: world.generator,
+            density_frequency: world.density_frequency,
+            density_squash: world.density_squash,
+            caves: world.caves.clone(),
+            ores: world.ores.clone(),
+            trees: world.trees.clone(),
+            water: world.water.clone(),
+            ..self.clone()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), TerrainConfigError> {
+        if self.chunk_size <= 0 {
+            return Err(TerrainConfigError::ChunkSizeNotPositive(self.chunk_size));
+        }
+        if self.view_distance <= 0 || self.view_distance % self.chunk_size != 0 {
+            return Err(TerrainConfigError::ViewDistanceNotMultipleOfChunkSize {
+                view_distance: self.view_distance,
+                chunk_size: self.chunk_size,
+            });
+        }
+        if self.vertical_view_distance <= 0 || self.vertical_view_distance % self.chunk_size != 0
+        {
+            return Err(
+                TerrainConfigError::VerticalViewDistanceNotMultipleOfChunkSize {
+                    vertical_view_distance: self.vertical_view_distance,
+                    chunk_size: self.chunk_size,
+                },
+            );
+        }
+        if self.unload_margin < 0 {
+            return Err(TerrainConfigError::UnloadMarginNegative(self.unload_margin));
+        }
+        if self.num_texture_layers == 0 {
+            return Err(TerrainConfigError::NoTextureLayers);
+        }
+        if self.density_squash <= 0.0 {
+            return Err(TerrainConfigError::DensitySquashNotPositive(
+                self.density_squash,
+            ));
+        }
+        for ore in &self.ores {
+            if ore.min_y > ore.max_y || ore.veins_per_chunk < 0.0 {
+                return Err(TerrainConfigError::InvalidOre(ore.voxel));
+            }
+        }
+        // Trees may only reach into the chunks next to the one that placed them.
+        let trees = &self.trees;
+        if trees.trees_per_chunk < 0.0
+            || trees.min_trunk_height < 1
+            || trees.min_trunk_height > trees.max_trunk_height
+            || trees.max_trunk_height + 2 > self.chunk_size
+            || trees.canopy_radius < 0
+            || trees.canopy_radius >= self.chunk_size
+        {
+            return Err(TerrainConfigError::InvalidTrees);
+        }
+        if self.max_generation_tasks == 0 || self.max_meshing_tasks == 0 {
+            return Err(TerrainConfigError::NoTasks);
+        }
+        if self.generations_per_frame == 0 || self.mesh_uploads_per_frame == 0 {
+            return Err(TerrainConfigError::NoFrameBudget);
+        }
+        if !(0.0..1.0).contains(&self.view_direction_bias) {
+            return Err(TerrainConfigError::ViewDirectionBiasOutOfRange(
+                self.view_direction_bias,
+            ));
+        }
+        if self.autosave_interval < 0.0 {
+            return Err(TerrainConfigError::AutosaveIntervalNegative(
+                self.autosave_interval,
+            ));
+        }
+        if self.noise_octaves == 0 || self.noise_octaves > noise::RidgedMulti::MAX_OCTAVES {
+            return Err(TerrainConfigError::OctavesOutOfRange(self.noise_octaves));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum TerrainConfigError {
+    Io(io::Error),
+    Ron(ron::Error),
+    Toml(toml::de::Error),
+    UnsupportedFormat(String),
+    ChunkSizeNotPositive(i32),
+    ViewDistanceNotMultipleOfChunkSize { view_distance: i32, chunk_size: i32 },
+    VerticalViewDistanceNotMultipleOfChunkSize { vertical_view_distance: i32, chunk_size: i32 },
+    UnloadMarginNegative(i32),
+    NoTextureLayers,
+    DensitySquashNotPositive(f64),
+    InvalidOre(Voxel),
+    InvalidTrees,
+    NoTasks,
+    NoFrameBudget,
+    ViewDirectionBiasOutOfRange(f32),
+    AutosaveIntervalNegative(f32),
+    OctavesOutOfRange(usize),
+}
+
+impl fmt::Display for TerrainConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read terrain config: {}", err),
+            Self::Ron(err) => write!(f, "failed to parse terrain config: {}", err),
+            Self::Toml(err) => write!(f, "failed to parse terrain config: {}", err),
+            Self::UnsupportedFormat(path) => {
+                write!(f, "terrain config {} is not a .ron or .toml file", path)
+            }
+            Self::ChunkSizeNotPositive(size) => {
+                write!(f, "chunk_size must be positive, got {}", size)
+            }
+            Self::ViewDistanceNotMultipleOfChunkSize {
+                view_distance,
+                chunk_size,
+            } => write!(
+                f,
+                "view_distance {} must be a positive multiple of chunk_size {}",
+                view_distance, chunk_size
+            ),
+            Self::VerticalViewDistanceNotMultipleOfChunkSize {
+                vertical_view_distance,
+                chunk_size,
+            } => write!(
+                f,
+                "vertical_view_distance {} must be a positive multiple of chunk_size {}",
+                vertical_view_distance, chunk_size
+            ),
+            Self::UnloadMarginNegative(margin) => {
+                write!(f, "unload_margin must not be negative, got {}", margin)
+            }
+            Self::NoTextureLayers => write!(f, "num_texture_layers must be at least 1"),
+            Self::DensitySquashNotPositive(squash) => {
+                write!(f, "density_squash must be positive, got {}", squash)
+            }
+            Self::InvalidOre(voxel) => write!(
+                f,
+                "ore {:?} needs min_y <= max_y and a non-negative veins_per_chunk",
+                voxel
+            ),
+            Self::InvalidTrees => write!(
+                f,
+                "trees need a non-negative trees_per_chunk, 1 <= min_trunk_height <= \
+                 max_trunk_height <= chunk_size - 2 and 0 <= canopy_radius < chunk_size"
+            ),
+            Self::NoTasks => write!(
+                f,
+                "max_generation_tasks and max_meshing_tasks must be at least 1"
+            ),
+            Self::NoFrameBudget => write!(
+                f,
+                "generations_per_frame and mesh_uploads_per_frame must be at least 1"
+            ),
+            Self::ViewDirectionBiasOutOfRange(bias) => write!(
+                f,
+                "view_direction_bias must be at least 0 and less than 1, got {}",
+                bias
+            ),
+            Self::AutosaveIntervalNegative(interval) => write!(
+                f,
+                "autosave_interval must not be negative, got {}",
+                interval
+            ),
+            Self::OctavesOutOfRange(octaves) => write!(
+                f,
+                "noise_octaves must be between 1 and {}, got {}",
+                noise::RidgedMulti::MAX_OCTAVES,
+                octaves
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TerrainConfigError {}
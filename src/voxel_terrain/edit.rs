@@ -0,0 +1,300 @@
+use super::{
+    generator::{GeneratedMeshesResource, GeneratedVoxelResource, Voxel},
+    history::{EditHistory, EditHistoryError, VoxelChange},
+    structures::chunk_key_for,
+};
+use bevy::{ecs::SystemParam, prelude::*};
+use building_blocks::{core::prelude::*, storage::prelude::*};
+use std::collections::HashMap;
+
+/// Reads and edits the voxels of loaded chunks. Every edit marks the chunks it touches as
+/// modified, so they are saved, and remeshes them along with any neighbour whose border they
+/// share.
+///
+/// Voxels in chunks that aren't loaded can't be edited; edits skip them. Every edit is recorded
+/// in the `EditHistory`, so it can be undone.
+#[derive(SystemParam)]
+pub struct VoxelWorld<'a> {
+    voxels: ResMut<'a, GeneratedVoxelResource>,
+    voxel_meshes: ResMut<'a, GeneratedMeshesResource>,
+    history: ResMut<'a, EditHistory>,
+}
+
+/// Voxels copied out of the world with `VoxelWorld::copy`.
+#[derive(Clone)]
+pub struct VoxelClipboard {
+    /// Still at the extent they were copied from.
+    voxels: Array3<Voxel>,
+}
+
+impl VoxelClipboard {
+    pub fn shape(&self) -> Point3i {
+        self.voxels.extent().shape
+    }
+
+    /// The copied voxel at `p`, relative to the copy's minimum.
+    pub fn get(&self, p: Point3i) -> Voxel {
+        self.voxels.get(&(self.voxels.extent().minimum + p))
+    }
+}
+
+impl<'a> VoxelWorld<'a> {
+    /// The voxel at `p`, or `None` if its chunk isn't loaded.
+    pub fn get(&self, p: Point3i) -> Option<Voxel> {
+        let key = chunk_key_for(p, self.voxels.chunk_shape());
+        if self.voxels.is_loaded(&key) {
+            Some(self.voxels.map.get(&p))
+        } else {
+            None
+        }
+    }
+
+    /// Sets the voxel at `p`. Returns whether it changed.
+    pub fn set(&mut self, p: Point3i, voxel: Voxel) -> bool {
+        let extent = Extent3i::from_min_and_shape(p, PointN([1; 3]));
+        self.edit(extent, |_, _| Some(voxel)) > 0
+    }
+
+    /// Sets every voxel in `extent`. Returns how many changed.
+    pub fn fill(&mut self, extent: Extent3i, voxel: Voxel) -> usize {
+        self.edit(extent, |_, _| Some(voxel))
+    }
+
+    /// Turns every `from` voxel in `extent` into `to`. Returns how many changed.
+    pub fn replace(&mut self, extent: Extent3i, from: Voxel, to: Voxel) -> usize {
+        self.edit(extent, |_, v| if v == from { Some(to) } else { None })
+    }
+
+    /// Sets every voxel within `radius` of `center`. Returns how many changed.
+    pub fn fill_sphere(&mut self, center: Point3i, radius: i32, voxel: Voxel) -> usize {
+        let extent = Extent3i::from_min_and_shape(
+            center - PointN([radius; 3]),
+            PointN([2 * radius + 1; 3]),
+        );
+        let r2 = radius * radius;
+        self.edit(extent, |p, _| {
+            let d = p - center;
+            if d.x() * d.x() + d.y() * d.y() + d.z() * d.z() <= r2 {
+                Some(voxel)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Sets every voxel in the upright cylinder standing on `base`, `height` voxels tall.
+    /// Returns how many changed.
+    pub fn fill_cylinder(
+        &mut self,
+        base: Point3i,
+        radius: i32,
+        height: i32,
+        voxel: Voxel,
+    ) -> usize {
+        let extent = Extent3i::from_min_and_shape(
+            base - PointN([radius, 0, radius]),
+            PointN([2 * radius + 1, height, 2 * radius + 1]),
+        );
+        let r2 = radius * radius;
+        self.edit(extent, |p, _| {
+            let d = p - base;
+            if d.x() * d.x() + d.z() * d.z() <= r2 {
+                Some(voxel)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Copies the voxels in `extent`. Voxels in chunks that aren't loaded are copied as air.
+    pub fn copy(&self, extent: Extent3i) -> VoxelClipboard {
+        let mut voxels = Array3::fill(extent, Voxel::AIR);
+        copy_extent(&extent, &self.voxels.map, &mut voxels);
+
+        VoxelClipboard { voxels }
+    }
+
+    /// Writes a copy back with its minimum at `min`. Air in the copy is skipped unless
+    /// `include_air` is set. Returns how many voxels changed.
+    pub fn paste(&mut self, clipboard: &VoxelClipboard, min: Point3i, include_air: bool) -> usize {
+        let extent = Extent3i::from_min_and_shape(min, clipboard.shape());
+        self.edit(extent, |p, _| {
+            let voxel = clipboard.get(p - min);
+            if include_air || voxel != Voxel::AIR {
+                Some(voxel)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Groups the edits made until `end_batch` into one step of the history, so they are undone
+    /// together.
+    pub fn begin_batch(&mut self) {
+        self.history.begin_batch();
+    }
+
+    pub fn end_batch(&mut self) {
+        self.history.end_batch();
+    }
+
+    /// Reverts the last edit, or batch of edits. Fails, leaving the history as it was, if the
+    /// edit touched a chunk that isn't loaded.
+    pub fn undo(&mut self) -> Result<(), EditHistoryError> {
+        let writes: Vec<(Point3i, Voxel)> = match self.history.peek_undo() {
+            Some(batch) => batch
+                .changes
+                .iter()
+                .rev()
+                .map(|change| (change.point(), change.before))
+                .collect(),
+            None => return Err(EditHistoryError::NothingToUndo),
+        };
+        self.write_all(&writes)?;
+        self.history.finish_undo();
+        Ok(())
+    }
+
+    /// Makes the last undone edit again.
+    pub fn redo(&mut self) -> Result<(), EditHistoryError> {
+        let writes: Vec<(Point3i, Voxel)> = match self.history.peek_redo() {
+            Some(batch) => batch
+                .changes
+                .iter()
+                .map(|change| (change.point(), change.after))
+                .collect(),
+            None => return Err(EditHistoryError::NothingToRedo),
+        };
+        self.write_all(&writes)?;
+        self.history.finish_redo();
+        Ok(())
+    }
+
+    /// Writes voxels from the history, without recording them again. Nothing is written unless
+    /// every chunk is loaded.
+    fn write_all(&mut self, writes: &[(Point3i, Voxel)]) -> Result<(), EditHistoryError> {
+        let chunk_shape = self.voxels.chunk_shape();
+        let mut bounds: HashMap<Point3i, (Point3i, Point3i)> = HashMap::new();
+        for (p, _) in writes.iter() {
+            let key = chunk_key_for(*p, chunk_shape);
+            if !self.voxels.is_loaded(&key) {
+                return Err(EditHistoryError::ChunkNotLoaded(key));
+            }
+            grow_bounds(bounds.entry(key).or_insert((*p, *p)), *p);
+        }
+
+        for (p, voxel) in writes.iter() {
+            *self.voxels.map.get_mut(p) = *voxel;
+        }
+        for (key, (lo, hi)) in bounds {
+            self.mark_changed(key, lo, hi);
+        }
+
+        Ok(())
+    }
+
+    /// Calls `edit` with every loaded voxel in `extent` and writes the voxel it returns, if any.
+    /// Returns how many voxels changed.
+    fn edit<F>(&mut self, extent: Extent3i, mut edit: F) -> usize
+    where
+        F: FnMut(Point3i, Voxel) -> Option<Voxel>,
+    {
+        let chunk_shape = self.voxels.chunk_shape();
+        let mut changes = Vec::new();
+        for key in chunk_keys_overlapping(extent, chunk_shape) {
+            if !self.voxels.is_loaded(&key) {
+                continue;
+            }
+
+            let (min, max) = overlap(key, key + chunk_shape, extent);
+            // Bounds of the voxels that changed in this chunk.
+            let mut bounds: Option<(Point3i, Point3i)> = None;
+            for z in min.z()..max.z() {
+                for y in min.y()..max.y() {
+                    for x in min.x()..max.x() {
+                        let p = PointN([x, y, z]);
+                        let v = self.voxels.map.get_mut(&p);
+                        let before = *v;
+                        match edit(p, before) {
+                            Some(voxel) if voxel != before => *v = voxel,
+                            _ => continue,
+                        }
+                        changes.push(VoxelChange {
+                            position: [x, y, z],
+                            before,
+                            after: *v,
+                        });
+                        grow_bounds(bounds.get_or_insert((p, p)), p);
+                    }
+                }
+            }
+            if let Some((lo, hi)) = bounds {
+                self.mark_changed(key, lo, hi);
+            }
+        }
+
+        let changed = changes.len();
+        self.history.record(changes);
+        changed
+    }
+
+    /// Marks chunk `key` modified after the voxels from `lo` to `hi`, inclusive, changed. Meshes
+    /// include one voxel of their neighbours, so those are remeshed too.
+    fn mark_changed(&mut self, key: Point3i, lo: Point3i, hi: Point3i) {
+        self.voxels.modified_chunks.insert(key);
+        let changed_extent = Extent3i::from_min_and_lub(lo, hi + PointN([1; 3])).padded(1);
+        for neighbour in chunk_keys_overlapping(changed_extent, self.voxels.chunk_shape()) {
+            if self.voxels.is_loaded(&neighbour) {
+                self.voxel_meshes.dirty_chunks.insert(neighbour);
+            }
+        }
+    }
+}
+
+/// Grows the inclusive bounds `(lo, hi)` to take in `p`.
+fn grow_bounds(bounds: &mut (Point3i, Point3i), p: Point3i) {
+    let (lo, hi) = bounds;
+    *lo = PointN([lo.x().min(p.x()), lo.y().min(p.y()), lo.z().min(p.z())]);
+    *hi = PointN([hi.x().max(p.x()), hi.y().max(p.y()), hi.z().max(p.z())]);
+}
+
+/// The minimum and least upper bound of the part of `extent` between `min` and `lub`. The
+/// result is empty, with some bound not above the minimum, if they don't overlap.
+fn overlap(min: Point3i, lub: Point3i, extent: Extent3i) -> (Point3i, Point3i) {
+    let extent_min = extent.minimum;
+    let extent_lub = extent.least_upper_bound();
+
+    (
+        PointN([
+            min.x().max(extent_min.x()),
+            min.y().max(extent_min.y()),
+            min.z().max(extent_min.z()),
+        ]),
+        PointN([
+            lub.x().min(extent_lub.x()),
+            lub.y().min(extent_lub.y()),
+            lub.z().min(extent_lub.z()),
+        ]),
+    )
+}
+
+/// Keys of every chunk that overlaps `extent`.
+pub(super) fn chunk_keys_overlapping(extent: Extent3i, chunk_shape: Point3i) -> Vec<Point3i> {
+    let shape = extent.shape;
+    if shape.x() <= 0 || shape.y() <= 0 || shape.z() <= 0 {
+        return Vec::new();
+    }
+
+    let min = chunk_key_for(extent.minimum, chunk_shape);
+    let max = chunk_key_for(extent.least_upper_bound() - PointN([1; 3]), chunk_shape);
+    let mut keys = Vec::new();
+    for z in (min.z()..=max.z()).step_by(chunk_shape.z() as usize) {
+        for y in (min.y()..=max.y()).step_by(chunk_shape.y() as usize) {
+            for x in (min.x()..=max.x()).step_by(chunk_shape.x() as usize) {
+                keys.push(PointN([x, y, z]));
+            }
+        }
+    }
+
+    keys
+}
@@ -1,19 +1,42 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
 use super::{
-    constants::*,
-    save_load::{load_chunk_from_file, save_chunk_to_file},
+    blocks::{BlockRegistry, BlockRegistryLoader, BlockRegistryResource, Face},
+    config::TerrainConfig,
+    edit::chunk_keys_overlapping,
+    history::{EditHistory, SavedHistory},
+    level::{LevelData, PlayerState},
+    migrations::CHUNK_FORMAT_VERSION,
+    ores::place_ores,
+    region::region_key_for,
+    water::fill_water,
+    structures::{
+        apply_writes, place_trees, writes_by_chunk, PendingWrites, StructureSources,
+        StructureWrite,
+    },
+    save_load::{
+        apply_deltas, compact_regions, load_chunk_from_file, quarantine_chunk, save_chunks,
+        ChunkSave, ChunkSaveInfo, LoadedChunk, LoadedVoxels, SaveMode, SaveStats,
+        WorldStorageError,
+    },
+    scheduler::{ChunkScheduler, Viewer},
+    terrain_generator::{TerrainGenerator, TerrainGeneratorResource},
+    worlds::ActiveWorld,
 };
 use building_blocks::{core::prelude::*, mesh::MaterialVoxel, storage::ChunkHashMap};
 use building_blocks::{
     mesh::{greedy_quads::*, PosNormTexMesh},
     storage::{prelude::*, IsEmpty},
 };
-use noise::{MultiFractal, NoiseFn, RidgedMulti, Seedable};
-use rand::Rng;
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 
 use bevy::{
+    app::AppExit,
     asset::LoadState,
     prelude::*,
     reflect::TypeUuid,
@@ -25,14 +48,15 @@ use bevy::{
         shader::{ShaderDefs, ShaderStages},
         texture::AddressMode,
     },
-    tasks::{ComputeTaskPool, TaskPool},
+    tasks::{AsyncComputeTaskPool, IoTaskPool, Task},
 };
 
+use bevy_prototype_character_controller::look::{LookDirection, LookEntity};
 use bevy_rapier3d::{
     physics::{ColliderHandleComponent, RigidBodyHandleComponent},
     rapier::{
         dynamics::{JointSet, RigidBodyBuilder, RigidBodyHandle, RigidBodySet},
-        geometry::{ColliderBuilder, ColliderSet},
+        geometry::{Collider, ColliderBuilder, ColliderSet},
     },
 };
 
@@ -49,17 +73,92 @@ pub struct VoxelTerrainGeneratorPlugin;
 
 impl Plugin for VoxelTerrainGeneratorPlugin {
     fn build(&self, builder: &mut AppBuilder) {
+        let mut config = builder
+            .resources()
+            .get::<TerrainConfig>()
+            .map(|config| (*config).clone())
+            .unwrap_or_default();
+
+        let world = builder
+            .resources()
+            .get::<ActiveWorld>()
+            .map(|world| (*world).clone())
+            .unwrap_or_default();
+
+        // An existing world keeps generating the terrain it was created with.
+        let level = LevelData::load(&world.dir)
+            .unwrap_or_else(|err| panic!("Failed to open world {:?}: {}", world.name, err));
+        if let Some(level) = &level {
+            config = config.with_world_settings(&level.config);
+        }
+        if let Err(err) = config.validate() {
+            panic!("Invalid terrain config: {}", err);
+        }
+        if builder
+            .resources()
+            .get::<TerrainGeneratorResource>()
+            .is_none()
+        {
+            builder.add_resource(TerrainGeneratorResource::from_config(&config));
+        }
+
+        let generator_id = builder
+            .resources()
+            .get::<TerrainGeneratorResource>()
+            .map(|generator| generator.0.id().to_string())
+            .unwrap();
+        if let Some(level) = &level {
+            if level.generator != generator_id {
+                warn!(
+                    "World was created with the {} generator, but {} is active",
+                    level.generator, generator_id
+                );
+            }
+        }
+        // An existing world keeps the generator and config it was created with. Only the format
+        // version moves on, since chunks are upgraded as they load.
+        let level = match level {
+            Some(level) => LevelData {
+                format_version: CHUNK_FORMAT_VERSION,
+                ..level
+            },
+            None => LevelData::new(&config, &generator_id),
+        };
+        if let Err(err) = level.save(&world.dir) {
+            warn!("Failed to save level data: {}", err);
+        }
+
+        let history = EditHistory::load(&world.dir, config.edit_history_depth)
+            .unwrap_or_else(|err| {
+                warn!("Failed to load edit history, starting a new one: {}", err);
+                EditHistory::new(config.edit_history_depth)
+            });
+
         builder
             .add_asset::<TerrainMaterial>()
+            .add_asset::<BlockRegistry>()
+            .init_asset_loader::<BlockRegistryLoader>()
+            .init_resource::<BlockRegistryResource>()
             .add_resource(State::new(PluginState::PreInit))
             .add_resource(MeshGeneratorState::new())
-            .add_resource::<GeneratedVoxelResource>(GeneratedVoxelResource::default())
+            .add_resource::<GeneratedVoxelResource>(GeneratedVoxelResource::new(&config))
+            .add_resource(ChunkTasks::new(&config, &world))
+            .add_resource(SaveQueue::new(&config))
+            .add_resource(history)
+            .add_resource(ChunkScheduler::new(config.view_direction_bias))
+            .add_resource(config)
+            .add_resource(world)
             .add_resource::<GeneratedMeshesResource>(GeneratedMeshesResource::default())
             .init_resource::<VoxelAssetHandles>()
             .add_stage_after(stage::UPDATE, STAGE, StateStage::<PluginState>::default())
             .on_state_enter(STAGE, PluginState::PreInit, load_assets.system())
             .on_state_update(STAGE, PluginState::PreInit, check_assets.system())
             .on_state_enter(STAGE, PluginState::Init, setup_generator_system.system())
+            .on_state_update(
+                STAGE,
+                PluginState::Finished,
+                update_chunk_scheduler_system.system(),
+            )
             .on_state_update(
                 STAGE,
                 PluginState::Finished,
@@ -69,7 +168,11 @@ impl Plugin for VoxelTerrainGeneratorPlugin {
                 STAGE,
                 PluginState::Finished,
                 generate_chunk_meshes_system.system(),
-            );
+            )
+            .on_state_update(STAGE, PluginState::Finished, unload_chunks_system.system())
+            .on_state_update(STAGE, PluginState::Finished, save_chunks_system.system())
+            .on_state_update(STAGE, PluginState::Finished, save_on_exit_system.system())
+            .on_state_update(STAGE, PluginState::Finished, reload_blocks_system.system());
         //.on_state_enter(STAGE, PluginState::Finished, voxel_generator_system.system())
     }
 }
@@ -78,6 +181,7 @@ fn load_assets(mut handles: ResMut<VoxelAssetHandles>, asset_server: Res<AssetSe
     const TERRAIN_TEXTURE_PATH: &str = "../assets/textures/terrain.png";
     const FRAGMENT_SHADER_PATH: &str = "../assets/shaders/voxel.frag";
     const VERTEX_SHADER_PATH: &str = "../assets/shaders/voxel.vert";
+    const BLOCK_REGISTRY_PATH: &str = "../assets/config/default.blocks";
     
     // Enable hot asset reloading
     asset_server.watch_for_changes().unwrap();
@@ -93,18 +197,77 @@ fn load_assets(mut handles: ResMut<VoxelAssetHandles>, asset_server: Res<AssetSe
     let frag_shader = asset_server.load::<Shader, _>(FRAGMENT_SHADER_PATH);
     handles.vec.push(frag_shader.clone_untyped());
     handles.frag_shader = frag_shader;
+
+    let blocks = asset_server.load::<BlockRegistry, _>(BLOCK_REGISTRY_PATH);
+    handles.vec.push(blocks.clone_untyped());
+    handles.blocks = blocks;
+}
+
+/// Swaps in the block registry when its file changes and remeshes every chunk with it.
+fn reload_blocks_system(
+    mut events: Local<EventReader<AssetEvent<BlockRegistry>>>,
+    registry_events: Res<Events<AssetEvent<BlockRegistry>>>,
+    registries: Res<Assets<BlockRegistry>>,
+    handles: Res<VoxelAssetHandles>,
+    mut blocks: ResMut<BlockRegistryResource>,
+    mut voxel_meshes: ResMut<GeneratedMeshesResource>,
+) {
+    for event in events.iter(&registry_events) {
+        // A registry that failed to load at startup is created when the file is fixed.
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            if *handle != handles.blocks {
+                continue;
+            }
+            if let Some(registry) = registries.get(handle) {
+                info!("Block registry reloaded, remeshing chunks");
+                blocks.0 = Arc::new(registry.clone());
+                let keys: Vec<Point3i> = voxel_meshes.generated_map.keys().cloned().collect();
+                voxel_meshes.stale_chunks.extend(keys);
+            }
+        }
+    }
 }
 
+/// Moves on once every asset is loaded. If the block registry fails to load, the built-in one is
+/// used instead; the game can't run without any of the others, so failing to load them panics.
 fn check_assets(
     mut state: ResMut<State<PluginState>>,
     handles: ResMut<VoxelAssetHandles>,
     asset_server: Res<AssetServer>,
+    mut blocks: ResMut<BlockRegistryResource>,
 ) {
-    if let LoadState::Loaded =
-        asset_server.get_group_load_state(handles.vec.iter().map(|handle| handle.id))
-    {
-        state.set_next(PluginState::Init).unwrap();
+    let blocks_id = handles.blocks.id;
+    let others = handles
+        .vec
+        .iter()
+        .map(|handle| handle.id)
+        .filter(|id| *id != blocks_id);
+    match asset_server.get_group_load_state(others) {
+        LoadState::Loaded => {}
+        LoadState::Failed => {
+            let failed: Vec<String> = handles
+                .vec
+                .iter()
+                .filter(|handle| asset_server.get_load_state(handle.id) == LoadState::Failed)
+                .map(|handle| match asset_server.get_handle_path(handle.id) {
+                    Some(path) => format!("{:?}", path.path()),
+                    None => format!("{:?}", handle.id),
+                })
+                .collect();
+            panic!("Failed to load terrain assets: {}", failed.join(", "));
+        }
+        _ => return,
+    }
+
+    match asset_server.get_load_state(blocks_id) {
+        LoadState::Loaded => {}
+        LoadState::Failed => {
+            warn!("Failed to load the block registry, using the built-in blocks");
+            blocks.0 = Arc::new(BlockRegistry::builtin());
+        }
+        _ => return,
     }
+    state.set_next(PluginState::Init).unwrap();
 }
 
 pub struct MeshGeneratorState {
@@ -125,41 +288,184 @@ pub struct VoxelAssetHandles {
     frag_shader: Handle<Shader>,
     texture: Handle<Texture>,
     material: Handle<TerrainMaterial>,
+    translucent_material: Handle<TerrainMaterial>,
     pipeline: Handle<PipelineDescriptor>,
+    translucent_pipeline: Handle<PipelineDescriptor>,
+    blocks: Handle<BlockRegistry>,
     vec: Vec<HandleUntyped>,
 }
 
 pub type VoxelMap = ChunkHashMap<[i32; 3], Voxel, ()>;
 
-struct GeneratedVoxelResource {
-    pub noise: RidgedMulti,
+/// The voxels of every loaded chunk, and where each chunk is in its lifecycle.
+pub struct GeneratedVoxelResource {
     pub chunk_size: i32,
     pub map: VoxelMap,
-    pub max_height: i32,
     pub view_distance: i32,
+    pub vertical_view_distance: i32,
     pub materials: Vec<Handle<StandardMaterial>>,
+    /// Where each chunk is in its lifecycle. Chunks that are missing are unloaded.
+    pub chunk_states: HashMap<Point3i, ChunkState>,
+    /// Loaded chunks that changed after they were generated. Only these are saved on unload,
+    /// since the rest can be generated again.
+    pub modified_chunks: HashSet<Point3i>,
+    /// Which neighbours have written their structures into each chunk in the map.
+    pub structure_sources: HashMap<Point3i, StructureSources>,
+    pub pending_writes: PendingWrites,
+    pub save_stats: SaveStats,
+    /// How far past the view distance a chunk must be before it is unloaded, in voxels.
+    pub unload_margin: i32,
 }
 
-impl Default for GeneratedVoxelResource {
-    fn default() -> Self {
+/// Chunks go through these in order, then back to unloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkState {
+    /// Being loaded from disk or generated in the background. Nothing is in the voxel map yet.
+    Generating,
+    /// Voxels are in the map, but there are no mesh entities.
+    Loaded,
+    /// Voxels are in the map and mesh entities are spawned.
+    Meshed,
+    /// Out of range. Its entities are despawned, then it is queued for saving if modified, or
+    /// removed from the map if not.
+    Unloading,
+    /// Waiting for its save to finish before it is removed from the map.
+    Saving,
+}
+
+impl GeneratedVoxelResource {
+    fn new(config: &TerrainConfig) -> Self {
         let builder = ChunkMapBuilder {
-            chunk_shape: PointN([CHUNK_SIZE, CHUNK_SIZE * MAX_CHUNK_HEIGHT, CHUNK_SIZE]),
+            chunk_shape: PointN([config.chunk_size; 3]),
             ambient_value: Voxel(0),
             default_chunk_metadata: (),
         };
 
         GeneratedVoxelResource {
-            noise: RidgedMulti::new()
-                .set_seed(1234)
-                .set_frequency(0.008)
-                .set_octaves(5),
-            chunk_size: CHUNK_SIZE,
+            chunk_size: config.chunk_size,
             map: builder.build_with_hash_map_storage(),
-            max_height: 256,
-            view_distance: VIEW_DISTANCE,
+            view_distance: config.view_distance,
+            vertical_view_distance: config.vertical_view_distance,
             materials: Vec::new(),
+            chunk_states: HashMap::new(),
+            modified_chunks: HashSet::new(),
+            structure_sources: HashMap::new(),
+            pending_writes: PendingWrites::default(),
+            save_stats: SaveStats::default(),
+            unload_margin: config.unload_margin,
+        }
+    }
+
+    /// Takes the chunk back after it was marked for unloading, along with any structure voxels
+    /// that were sent its way in the meantime.
+    fn reload_chunk(&mut self, key: Point3i) {
+        self.chunk_states.insert(key, ChunkState::Loaded);
+        if self.apply_pending_writes(key) {
+            self.modified_chunks.insert(key);
         }
     }
+
+    /// Writes the structure voxels other chunks placed in the chunk at `key` into the map,
+    /// skipping chunks whose structures it already has. Returns whether any voxel changed.
+    fn apply_pending_writes(&mut self, key: Point3i) -> bool {
+        let chunk_shape = self.chunk_shape();
+        let mut sources = self.structure_sources.get(&key).copied().unwrap_or_default();
+        let mut changed = false;
+        for (source, writes) in self.pending_writes.get(key) {
+            if sources.contains(key, *source, chunk_shape) {
+                continue;
+            }
+            sources.insert(key, *source, chunk_shape);
+            for (p, voxel) in writes.iter() {
+                let v = self.map.get_mut(p);
+                if *v == Voxel::AIR {
+                    *v = *voxel;
+                    changed = true;
+                }
+            }
+        }
+        self.structure_sources.insert(key, sources);
+
+        changed
+    }
+
+    /// Removes the chunk's voxels from the map.
+    fn drop_chunk(&mut self, key: Point3i) {
+        self.map.storage_mut().remove(&key);
+        self.chunk_states.remove(&key);
+        self.structure_sources.remove(&key);
+    }
+
+    /// A copy of the chunk for `save_chunks`, without a baseline.
+    fn chunk_save(&self, key: Point3i) -> ChunkSave {
+        let chunk_extent = Extent3i::from_min_and_shape(key, self.chunk_shape());
+        ChunkSave {
+            key,
+            voxels: copy_chunk(&self.map, chunk_extent),
+            structures: self
+                .structure_sources
+                .get(&key)
+                .copied()
+                .unwrap_or_default(),
+            baseline: None,
+        }
+    }
+
+    /// Whether the chunk's voxels are in the map.
+    pub fn is_loaded(&self, key: &Point3i) -> bool {
+        matches!(
+            self.chunk_states.get(key),
+            Some(ChunkState::Loaded) | Some(ChunkState::Meshed)
+        )
+    }
+
+    /// Whether the chunk's voxels are in the map, including chunks on their way out.
+    fn has_voxels(&self, key: &Point3i) -> bool {
+        !matches!(self.chunk_states.get(key), Some(ChunkState::Generating) | None)
+    }
+
+    /// Whether chunk `key` is within view distance of `center`, grown by `margin` voxels. With
+    /// no margin these are exactly the chunks `chunks_in_view` returns, so nothing it loads is
+    /// unloaded straight away.
+    fn is_in_range(&self, key: Point3i, center: Point3i, margin: i32) -> bool {
+        let d = key - center;
+        let horizontal = self.view_distance + margin;
+        let vertical = self.vertical_view_distance + margin;
+
+        // Rounded out to chunk boundaries the same way `chunks_in_view` rounds its extent.
+        d.x() * d.x() + d.z() * d.z() <= horizontal * horizontal
+            && key.y() >= modulo_down(center.y() - vertical, self.chunk_size)
+            && key.y() <= modulo_up(center.y() + vertical, self.chunk_size)
+    }
+
+    pub fn chunk_shape(&self) -> Point3i {
+        PointN([self.chunk_size; 3])
+    }
+
+    /// Keys of every chunk within view distance of `center`, which is in voxel coordinates.
+    fn chunks_in_view(&self, center: Point3i) -> Vec<Point3i> {
+        let extent = transform_to_extent(center, self.view_distance, self.vertical_view_distance);
+        let extent = extent_modulo_expand(extent, self.chunk_size);
+        let min = extent.minimum;
+        let max = extent.least_upper_bound();
+
+        let vd2 = self.view_distance * self.view_distance;
+        let mut keys = Vec::new();
+        for y in (min.y()..max.y()).step_by(self.chunk_size as usize) {
+            for z in (min.z()..max.z()).step_by(self.chunk_size as usize) {
+                for x in (min.x()..max.x()).step_by(self.chunk_size as usize) {
+                    let p = PointN([x, y, z]);
+                    let d = p - center;
+                    // View distance is a cylinder; the extent already limits the height.
+                    if d.x() * d.x() + d.z() * d.z() <= vd2 {
+                        keys.push(p);
+                    }
+                }
+            }
+        }
+
+        keys
+    }
 }
 
 type VoxelType = u8;
@@ -168,6 +474,19 @@ pub struct Voxel(pub VoxelType);
 
 impl Voxel {
     pub const AIR: Self = Self(0);
+    pub const GRASS: Self = Self(1);
+    pub const DIRT: Self = Self(2);
+    pub const STONE: Self = Self(3);
+    pub const GRAVEL: Self = Self(4);
+    pub const SAND: Self = Self(5);
+    pub const SNOW: Self = Self(6);
+    pub const COAL_ORE: Self = Self(7);
+    pub const IRON_ORE: Self = Self(8);
+    pub const GOLD_ORE: Self = Self(9);
+    pub const WOOD: Self = Self(10);
+    pub const LEAVES: Self = Self(11);
+    pub const WATER: Self = Self(12);
+    pub const GLASS: Self = Self(13);
 
     pub fn set(&mut self, value: VoxelType) {
         self.0 = value;
@@ -212,13 +531,37 @@ fn setup_generator_system(
     mut textures: ResMut<Assets<Texture>>,
     mut render_graph: ResMut<RenderGraph>,
     mut handles: ResMut<VoxelAssetHandles>,
+    registries: Res<Assets<BlockRegistry>>,
+    mut blocks: ResMut<BlockRegistryResource>,
+    config: Res<TerrainConfig>,
 ) {
+    if let Some(registry) = registries.get(&handles.blocks) {
+        if registry.max_texture_layer() > config.num_texture_layers {
+            warn!(
+                "Block registry uses texture layer {}, but there are only {} layers",
+                registry.max_texture_layer(),
+                config.num_texture_layers
+            );
+        }
+        blocks.0 = Arc::new(registry.clone());
+    }
+
     // Create a new shader pipeline
     let pipeline_handle = pipelines.add(PipelineDescriptor::default_config(ShaderStages {
         vertex: handles.vert_shader.clone(),
         fragment: Some(handles.frag_shader.clone()),
     }));
 
+    // Translucent faces blend over what is behind them, so they must not write depth
+    let mut translucent_pipeline = PipelineDescriptor::default_config(ShaderStages {
+        vertex: handles.vert_shader.clone(),
+        fragment: Some(handles.frag_shader.clone()),
+    });
+    if let Some(depth_stencil) = translucent_pipeline.depth_stencil_state.as_mut() {
+        depth_stencil.depth_write_enabled = false;
+    }
+    let translucent_pipeline_handle = pipelines.add(translucent_pipeline);
+
     // Add an AssetRenderResourcesNode to our Render Graph. This will bind TerrainMaterial resources to our shader
     render_graph.add_system_node(
         "terrain_material",
@@ -238,6 +581,13 @@ fn setup_generator_system(
         shaded: true,
     });
 
+    let translucent_material_handle = materials.add(TerrainMaterial {
+        albedo: Color::rgb(1.0, 1.0, 1.0),
+        albedo_texture: Some(handles.texture.clone()),
+        custom_val: 0.0,
+        shaded: true,
+    });
+
     let texture = textures.get_mut(handles.texture.clone()).unwrap();
 
     texture.sampler.address_mode_u = AddressMode::Repeat;
@@ -245,32 +595,46 @@ fn setup_generator_system(
     texture.sampler.address_mode_w = AddressMode::Repeat;
 
     // Create a new array texture asset from the loaded texture.
-    let array_layers = NUM_TEXTURE_LAYERS + 1;
+    let array_layers = config.num_texture_layers + 1;
     texture.reinterpret_stacked_2d_as_array(array_layers);
 
     handles.material = material_handle;
+    handles.translucent_material = translucent_material_handle;
     handles.pipeline = pipeline_handle;
+    handles.translucent_pipeline = translucent_pipeline_handle;
 
     state.set_next(PluginState::Finished).unwrap();
 }
 
-fn create_mesh_entity(
+/// Spawns a chunk's translucent mesh. It is only rendered, without a collider, and is sorted back
+/// to front with the other transparent entities. Transparent entities are sorted by translation,
+/// so the entity sits at the chunk's minimum and its vertices are relative to that.
+fn create_translucent_entity(
     mesh_data: ChunkMeshData,
+    chunk_min: Point3i,
     commands: &mut Commands,
-    voxel_material: Handle<TerrainMaterial>,
-    pipelines: RenderPipelines,
+    material: &ChunkMaterial,
     meshes: &mut Assets<Mesh>,
-) -> Entity {
+) -> ChunkRenderMesh {
     let mesh = mesh_data.pos_norm_tex_mesh;
 
     assert_eq!(mesh.positions.len(), mesh.normals.len());
 
-    let _num_vertices = mesh.positions.len();
+    let translation = Vec3::new(
+        chunk_min.x() as f32,
+        chunk_min.y() as f32,
+        chunk_min.z() as f32,
+    );
+    let positions = mesh
+        .positions
+        .iter()
+        .map(|[x, y, z]| [x - translation.x, y - translation.y, z - translation.z])
+        .collect();
 
     let mut render_mesh = Mesh::new(PrimitiveTopology::TriangleList);
     render_mesh.set_attribute(
         Mesh::ATTRIBUTE_POSITION,
-        VertexAttributeValues::Float3(mesh.positions),
+        VertexAttributeValues::Float3(positions),
     );
     render_mesh.set_attribute(
         Mesh::ATTRIBUTE_NORMAL,
@@ -294,16 +658,39 @@ fn create_mesh_entity(
         mesh.indices.iter().map(|i| *i as u32).collect(),
     )));
 
-    commands
+    let mesh = meshes.add(render_mesh);
+    let entity = commands
         .spawn(MeshBundle {
-            mesh: meshes.add(render_mesh),
-            render_pipelines: pipelines,
-            transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.0)),
+            mesh: mesh.clone(),
+            render_pipelines: material.pipelines.clone(),
+            visible: Visible {
+                is_visible: true,
+                is_transparent: true,
+            },
+            transform: Transform::from_translation(translation),
             ..Default::default()
         })
-        .with(voxel_material)
+        .with(material.material.clone())
         .current_entity()
-        .unwrap()
+        .unwrap();
+    (entity, mesh)
+}
+
+/// What a chunk's mesh is drawn with.
+struct ChunkMaterial {
+    material: Handle<TerrainMaterial>,
+    pipelines: RenderPipelines,
+}
+
+impl ChunkMaterial {
+    fn new(material: &Handle<TerrainMaterial>, pipeline: &Handle<PipelineDescriptor>) -> Self {
+        Self {
+            material: material.clone(),
+            pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                pipeline.clone(),
+            )]),
+        }
+    }
 }
 
 struct ChunkMeshData {
@@ -312,6 +699,30 @@ struct ChunkMeshData {
     vert_ao_vals: Vec<f32>,
 }
 
+impl ChunkMeshData {
+    fn append(&mut self, other: ChunkMeshData) {
+        let offset = self.pos_norm_tex_mesh.positions.len();
+        let mesh = other.pos_norm_tex_mesh;
+        self.pos_norm_tex_mesh.positions.extend(mesh.positions);
+        self.pos_norm_tex_mesh.normals.extend(mesh.normals);
+        self.pos_norm_tex_mesh.tex_coords.extend(mesh.tex_coords);
+        self.pos_norm_tex_mesh
+            .indices
+            .extend(mesh.indices.into_iter().map(|i| i + offset));
+        self.vert_vox_mat_vals.extend(other.vert_vox_mat_vals);
+        self.vert_ao_vals.extend(other.vert_ao_vals);
+    }
+}
+
+/// Opaque and transparent voxels are meshed separately. Only the opaque mesh gets a collider,
+/// and the translucent mesh is drawn with blending.
+struct ChunkMeshes {
+    opaque: Option<ChunkMeshData>,
+    translucent: Option<ChunkMeshData>,
+    /// Built from the solid blocks, which aren't always the opaque ones.
+    collider: Option<Collider>,
+}
+
 fn get_ao_at_vert(
     v: Point3f,
     padded_chunk: &ArrayN<[i32; 3], Voxel>,
@@ -410,108 +821,380 @@ fn get_ao_at_vert(
     }
 }
 
-fn get_chunk_voxels(noise: &RidgedMulti, extent: Extent3i) -> Array3<Voxel> {
+#[derive(Bundle)]
+pub struct GenerateAtTag;
+/// The opaque mesh and the collider share an entity, which has whichever of them the chunk has.
+type ChunkEntityMesh = (Entity, Option<Handle<Mesh>>, Option<RigidBodyHandle>);
+type ChunkRenderMesh = (Entity, Handle<Mesh>);
+
+/// Everything spawned for one chunk. Either part is missing if it had no faces.
+struct ChunkEntities {
+    opaque: Option<ChunkEntityMesh>,
+    translucent: Option<ChunkRenderMesh>,
+}
 
-    let mut voxels = Array3::fill(extent, Voxel::AIR);
-    let mut rng = rand::thread_rng();
-    let random_material = rng.gen_range(1, 4);
-    let yoffset = SEA_LEVEL;
-    let yscale = TERRAIN_Y_SCALE * yoffset;
+/// Entities of every meshed chunk.
+pub struct GeneratedMeshesResource {
+    pub generated_map: HashMap<Point3i, ChunkEntities>,
+    /// Meshed chunks that were edited. They are meshed again on the spot.
+    pub dirty_chunks: HashSet<Point3i>,
+    /// Meshed chunks that are out of date, but not because of an edit: a neighbour loaded after
+    /// their border was read, a neighbour's trees reached into them, or the block registry
+    /// changed. They are meshed again along with new chunks in view, within the task budget.
+    pub stale_chunks: HashSet<Point3i>,
+}
 
-    let min = extent.minimum;
-    let max = extent.least_upper_bound();
+impl Default for GeneratedMeshesResource {
+    fn default() -> Self {
+        GeneratedMeshesResource {
+            generated_map: HashMap::new(),
+            dirty_chunks: HashSet::new(),
+            stale_chunks: HashSet::new(),
+        }
+    }
+}
 
-    let mut set_flag = false;
+/// Background generation and meshing jobs, keyed by chunk.
+struct ChunkTasks {
+    config: Arc<TerrainConfig>,
+    generating: HashMap<Point3i, Task<GeneratedChunk>>,
+    meshing: HashMap<Point3i, Task<ChunkMeshes>>,
+    storage: Arc<WorldStorage>,
+}
 
-    voxels.for_each_mut(&extent, |p: Point3i, v: &mut Voxel| {
-        // let local_p = p - min;
-        let x = p.x();
-        let y = p.y();
-        let z = p.z();
-        
-        if y == 0 {
-            *v = Voxel(0);
-        } else {
-            let max_y = (noise.get([x as f64, z as f64]) * yscale + yoffset).round() as i32;
-            if y <= max_y {
-                // Set voxel value
-                *v = Voxel(random_material);
+impl ChunkTasks {
+    fn new(config: &TerrainConfig, world: &ActiveWorld) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            generating: HashMap::new(),
+            meshing: HashMap::new(),
+            storage: Arc::new(WorldStorage {
+                dir: world.dir.clone(),
+                lock: Mutex::new(()),
+            }),
+        }
+    }
+}
+
+/// The active world's directory, shared with the background load and save tasks.
+struct WorldStorage {
+    dir: PathBuf,
+    /// Held while reading or writing region files, so loads never see a save half done.
+    lock: Mutex<()>,
+}
+
+impl WorldStorage {
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().expect("World storage lock poisoned")
+    }
+}
+
+type SaveResult = (Vec<(Point3i, ChunkSaveInfo)>, Vec<WorldStorageError>);
+
+/// Chunks, player state and edit history waiting to be written, and the background save writing
+/// the previous batch. Only one save runs at a time, so two saves of the same chunk land in order.
+struct SaveQueue {
+    chunks: Vec<ChunkSave>,
+    player: Option<PlayerState>,
+    history: Option<SavedHistory>,
+    in_flight: Option<(Vec<Point3i>, Task<SaveResult>)>,
+    /// `None` when autosave is off.
+    autosave_timer: Option<Timer>,
+    /// Regions written this session, which are compacted on exit.
+    written_regions: HashSet<Point3i>,
+}
+
+impl SaveQueue {
+    fn new(config: &TerrainConfig) -> Self {
+        Self {
+            chunks: Vec::new(),
+            player: None,
+            history: None,
+            in_flight: None,
+            autosave_timer: if config.autosave_interval > 0.0 {
+                Some(Timer::from_seconds(config.autosave_interval, true))
+            } else {
+                None
+            },
+            written_regions: HashSet::new(),
+        }
+    }
+
+    /// Whether a save that includes the chunk is queued or running. The chunk must stay in the
+    /// map until it is done, or it could be loaded again from the older copy on disk.
+    fn is_saving(&self, key: &Point3i) -> bool {
+        self.chunks.iter().any(|chunk| chunk.key == *key)
+            || self
+                .in_flight
+                .as_ref()
+                .map_or(false, |(keys, _)| keys.contains(key))
+    }
+}
+
+/// A chunk fresh off the disk or out of the generator. Structures are returned rather than
+/// applied, since some of their voxels may belong to other chunks.
+pub(super) struct GeneratedChunk {
+    pub(super) voxels: Array3<Voxel>,
+    pub(super) trees: Vec<StructureWrite>,
+    /// For a chunk loaded from disk, the neighbours whose structures are already in its voxels.
+    /// Its own trees are in there too, so `trees` only goes to its neighbours.
+    structures: Option<StructureSources>,
+}
+
+/// Loads the chunk if it was saved, and generates it otherwise. Chunks saved in full already
+/// contain their trees, but are generated again to find the parts that reach into their
+/// neighbours; the trees of chunks saved as deltas are in the baseline.
+fn load_or_generate_chunk(
+    generator: &dyn TerrainGenerator,
+    config: &TerrainConfig,
+    storage: &WorldStorage,
+    chunk_extent: Extent3i,
+) -> GeneratedChunk {
+    let p = chunk_extent.minimum;
+    let loaded = {
+        let _lock = storage.lock();
+        let loaded = load_chunk_from_file(&storage.dir, p, chunk_extent);
+        if let Err(err) = &loaded {
+            if err.is_corrupt() {
+                if let Err(err) = quarantine_chunk(&storage.dir, p, chunk_extent.shape.x()) {
+                    warn!("Failed to quarantine chunk {:?}: {}", p, err);
+                }
             }
         }
+        loaded
+    };
 
-        let mut set_flag = true;
-  
-    });
+    match loaded {
+        Ok(Some(LoadedChunk {
+            voxels: LoadedVoxels::Full(voxels),
+            structures,
+        })) => {
+            return GeneratedChunk {
+                voxels,
+                trees: generate_chunk(generator, config, chunk_extent).trees,
+                structures: Some(structures),
+            }
+        }
+        Ok(Some(LoadedChunk {
+            voxels: LoadedVoxels::Delta(deltas),
+            structures,
+        })) => {
+            let mut chunk = generate_baseline(generator, config, chunk_extent);
+            apply_deltas(&mut chunk.voxels, chunk_extent, &deltas);
+            chunk.structures = Some(structures);
+            return chunk;
+        }
+        Ok(None) => {}
+        Err(err) if err.is_corrupt() => {
+            warn!("Chunk {:?} is corrupt, generating it again: {}", p, err)
+        }
+        Err(err) => warn!("Failed to load chunk {:?}, generating it instead: {}", p, err),
+    }
 
-    voxels
+    generate_chunk(generator, config, chunk_extent)
 }
 
-#[derive(Bundle)]
-pub struct GenerateAtTag;
-type ChunkEntityMesh = (Entity, Handle<Mesh>, RigidBodyHandle);
+pub(super) fn generate_chunk(
+    generator: &dyn TerrainGenerator,
+    config: &TerrainConfig,
+    chunk_extent: Extent3i,
+) -> GeneratedChunk {
+    // One layer more than the chunk, so trees can tell whether grass on its top layer has air
+    // above it.
+    let extended_extent = Extent3i::from_min_and_shape(
+        chunk_extent.minimum,
+        chunk_extent.shape + PointN([0, 1, 0]),
+    );
+    let mut extended = generator.generate(extended_extent);
+    place_ores(&mut extended, chunk_extent, config.seed, &config.ores);
+    fill_water(&mut extended, extended_extent, config.water.water_level, |x, z| {
+        generator.surface_height(x, z)
+    });
+    let trees = place_trees(&extended, chunk_extent, config.seed, &config.trees);
 
-struct GeneratedMeshesResource {
-    pub generated_map: HashMap<Point3i, Option<ChunkEntityMesh>>,
+    let mut voxels = Array3::fill(chunk_extent, Voxel::AIR);
+    copy_extent(&chunk_extent, &extended, &mut voxels);
+
+    GeneratedChunk {
+        voxels,
+        trees,
+        structures: None,
+    }
 }
 
-impl Default for GeneratedMeshesResource {
-    fn default() -> Self {
-        GeneratedMeshesResource {
-            generated_map: HashMap::new(),
+/// What delta saves are diffed against: the generated chunk with its own trees written in. Tree
+/// voxels that neighbours wrote into the chunk aren't in it, so they are saved as deltas, and
+/// the chunk's `StructureSources` keep them from being written again.
+fn generate_baseline(
+    generator: &dyn TerrainGenerator,
+    config: &TerrainConfig,
+    chunk_extent: Extent3i,
+) -> GeneratedChunk {
+    let mut chunk = generate_chunk(generator, config, chunk_extent);
+    apply_writes(&mut chunk.voxels, chunk_extent, &chunk.trees);
+
+    chunk
+}
+
+/// Rebuilds the load queue when a `GenerateAtTag` entity moves to another chunk or turns, and
+/// cancels work on chunks that are no longer in view of any of them. An entity with a
+/// `LookEntity` faces where that entity looks; any other faces along its own rotation.
+fn update_chunk_scheduler_system(
+    mut voxels: ResMut<GeneratedVoxelResource>,
+    mut scheduler: ResMut<ChunkScheduler>,
+    mut tasks: ResMut<ChunkTasks>,
+    query: Query<(&GlobalTransform, Option<&LookEntity>), With<GenerateAtTag>>,
+    looks: Query<&LookDirection>,
+) {
+    let viewers: Vec<Viewer> = query
+        .iter()
+        .map(|(transform, look_entity)| {
+            // The player's body never turns; the camera does.
+            let forward = look_entity
+                .and_then(|look_entity| looks.get(look_entity.0).ok())
+                .map_or(transform.rotation * -Vec3::unit_z(), |look| look.forward);
+            Viewer {
+                position: transform.translation,
+                forward,
+            }
+        })
+        .collect();
+
+    let chunk_shape = voxels.chunk_shape();
+    if !scheduler.needs_rebuild(&viewers, chunk_shape) {
+        return;
+    }
+
+    let mut keys = HashSet::new();
+    for viewer in viewers.iter() {
+        let center = PointN([
+            viewer.position.x.round() as i32,
+            viewer.position.y.round() as i32,
+            viewer.position.z.round() as i32,
+        ]);
+        keys.extend(voxels.chunks_in_view(center));
+    }
+
+    // Dropping a task cancels it.
+    for p in scheduler.rebuild(&viewers, chunk_shape, keys) {
+        if tasks.generating.remove(&p).is_some() {
+            voxels.chunk_states.remove(&p);
         }
+        tasks.meshing.remove(&p);
     }
 }
 
 fn generate_chunks_system(
     mut voxels: ResMut<GeneratedVoxelResource>,
-    voxel_meshes: Res<GeneratedMeshesResource>,
-    query: Query<&Transform, With<GenerateAtTag>>,
+    mut voxel_meshes: ResMut<GeneratedMeshesResource>,
+    mut tasks: ResMut<ChunkTasks>,
+    mut scheduler: ResMut<ChunkScheduler>,
+    generator: Res<TerrainGeneratorResource>,
+    task_pool: Res<AsyncComputeTaskPool>,
 ) {
-    let cam_transform = query.iter().next().expect("Failed to get camera transform");
-    let cam_pos = cam_transform.translation;
-    let cam_pos = PointN([cam_pos.x.round() as i32, 0i32, cam_pos.z.round() as i32]);
+    let chunk_shape = voxels.chunk_shape();
 
-    let extent = transform_to_extent(cam_pos, voxels.view_distance);
-    let extent = extent_modulo_expand(extent, voxels.chunk_size);
-    let min = extent.minimum;
-    let max = extent.least_upper_bound();
-
-    let chunk_size = voxels.chunk_size;
-    let max_height = voxels.max_height;
-    let vd2 = voxels.view_distance * voxels.view_distance;
-    for z in (min.z()..max.z()).step_by(voxels.chunk_size as usize) {
-        for x in (min.x()..max.x()).step_by(voxels.chunk_size as usize) {
-            let p = PointN([x, 0, z]);
-            let d = p - cam_pos;
-            if voxel_meshes.generated_map.get(&p).is_some() || d.dot(&d) > vd2 {
-                continue;
+    let mut finished = Vec::new();
+    for (p, task) in tasks.generating.iter_mut() {
+        if let Some(chunk) = task.now_or_never() {
+            finished.push((*p, chunk));
+        }
+    }
+    for (p, chunk) in finished {
+        tasks.generating.remove(&p);
+
+        let chunk_extent = Extent3i::from_min_and_shape(p, chunk_shape);
+        let GeneratedChunk {
+            voxels: mut chunk_voxels,
+            trees,
+            structures,
+        } = chunk;
+
+        // A saved chunk already has its own trees, and those of the neighbours that wrote into
+        // it before it was saved.
+        let structures = structures.unwrap_or_else(|| {
+            apply_writes(&mut chunk_voxels, chunk_extent, &trees);
+            StructureSources::default()
+        });
+        voxels.map.write_chunk(p, Chunk::with_array(chunk_voxels));
+        voxels.chunk_states.insert(p, ChunkState::Loaded);
+        voxels.structure_sources.insert(p, structures);
+        voxels.apply_pending_writes(p);
+
+        spill_structure_writes(&mut voxels, &mut voxel_meshes, p, &trees);
+
+        for neighbour in chunk_keys_overlapping(chunk_extent.padded(1), chunk_shape) {
+            if voxel_meshes.generated_map.contains_key(&neighbour)
+                || tasks.meshing.contains_key(&neighbour)
+            {
+                voxel_meshes.stale_chunks.insert(neighbour);
             }
+        }
+    }
 
-            let chunk_extent = Extent3i::from_min_and_shape(p, PointN([chunk_size, max_height, chunk_size]));
-
-            let chunk_voxels = get_chunk_voxels(
-                &voxels.noise,
-                chunk_extent,
-            );
-            
-            voxels.map.write_chunk(p, Chunk::with_array(chunk_voxels));
+    let max_tasks = tasks.config.max_generation_tasks;
+    let mut budget = tasks.config.generations_per_frame;
+    while budget > 0 && tasks.generating.len() < max_tasks {
+        let p = match scheduler.pop() {
+            Some(p) => p,
+            None => break,
+        };
+        if voxels.chunk_states.contains_key(&p) {
+            continue;
+        }
+        budget -= 1;
+
+        let generator = generator.0.clone();
+        let config = tasks.config.clone();
+        let storage = tasks.storage.clone();
+        let chunk_extent = Extent3i::from_min_and_shape(p, chunk_shape);
+        let task = task_pool.spawn(async move {
+            load_or_generate_chunk(&*generator, &config, &storage, chunk_extent)
+        });
+        tasks.generating.insert(p, task);
+        voxels.chunk_states.insert(p, ChunkState::Generating);
+    }
+}
 
+/// Sends the parts of a chunk's structures that fall outside it to the chunks they belong to.
+/// They are kept for when those chunks are generated, and written now into loaded chunks that
+/// don't have them yet, which are then remeshed.
+fn spill_structure_writes(
+    voxels: &mut GeneratedVoxelResource,
+    voxel_meshes: &mut GeneratedMeshesResource,
+    chunk_key: Point3i,
+    writes: &[StructureWrite],
+) {
+    let chunk_shape = voxels.chunk_shape();
+    for (key, writes) in writes_by_chunk(writes, chunk_key, chunk_shape) {
+        voxels.pending_writes.insert(key, chunk_key, writes);
+        if voxels.is_loaded(&key) && voxels.apply_pending_writes(key) {
+            voxels.modified_chunks.insert(key);
+            voxel_meshes.stale_chunks.insert(key);
         }
     }
 }
 
 fn modulo_down(v: i32, modulo: i32) -> i32 {
-    (v / modulo) * modulo
+    v.div_euclid(modulo) * modulo
 }
 
 fn modulo_up(v: i32, modulo: i32) -> i32 {
-    ((v / modulo) + 1) * modulo
+    (v.div_euclid(modulo) + 1) * modulo
 }
 
-fn transform_to_extent(cam_pos: Point3i, view_distance: i32) -> Extent3i {
+fn transform_to_extent(cam_pos: Point3i, view_distance: i32, vertical_view_distance: i32) -> Extent3i {
     Extent3i::from_min_and_lub(
-        PointN([cam_pos.x() - view_distance, 0, cam_pos.z() - view_distance]),
-        PointN([cam_pos.x() + view_distance, 0, cam_pos.z() + view_distance]),
+        PointN([
+            cam_pos.x() - view_distance,
+            cam_pos.y() - vertical_view_distance,
+            cam_pos.z() - view_distance,
+        ]),
+        PointN([
+            cam_pos.x() + view_distance,
+            cam_pos.y() + vertical_view_distance,
+            cam_pos.z() + view_distance,
+        ]),
     )
 }
 
@@ -521,31 +1204,22 @@ fn extent_modulo_expand(extent: Extent3i, modulo: i32) -> Extent3i {
     Extent3i::from_min_and_lub(
         PointN([
             modulo_down(min.x(), modulo),
-            min.y(),
+            modulo_down(min.y(), modulo),
             modulo_down(min.z(), modulo),
         ]),
         PointN([
             modulo_up(max.x(), modulo) + 1,
-            max.y() + 1,
+            modulo_up(max.y(), modulo) + 1,
             modulo_up(max.z(), modulo) + 1,
         ]),
     )
 }
 
-fn texture_layer_from_voxel_type(voxel_type: VoxelType) -> (i32, i32, i32) {
-    match voxel_type {
-        1 => (1, 2, 3),
-        2 => (3, 3, 3),
-        3 => (4, 4, 4),
-        4 => (5, 5, 5),
-        _ => (0, 0, 0),
-    }
-}
-
 fn process_quad_buffer(
     buffer: GreedyQuadsBuffer<VoxelType>,
     padded_chunk: &ArrayN<[i32; 3], Voxel>,
     padded_chunk_extent: &Extent3i,
+    blocks: &BlockRegistry,
 ) -> Option<ChunkMeshData> {
     let mut vert_vox_mat_vals: Vec<f32> = Vec::new();
     let mut vert_ao_vals: Vec<f32> = Vec::new();
@@ -559,18 +1233,16 @@ fn process_quad_buffer(
 
             group.face.add_quad_to_pos_norm_tex_mesh(&quad, &mut mesh);
 
-            let (top_tex_layer, sides_tex_layer, bottom_tex_layer) =
-                texture_layer_from_voxel_type(*material);
-            let voxel_mat = match group.face.n {
-                PointN([0, 1, 0]) => {
-                    if group.face.n_sign > 0 {
-                        top_tex_layer
-                    } else {
-                        bottom_tex_layer
-                    }
-                }
-                _ => sides_tex_layer,
-            } as f32;
+            let positive = group.face.n_sign > 0;
+            let face = match group.face.n {
+                PointN([0, 1, 0]) if positive => Face::Top,
+                PointN([0, 1, 0]) => Face::Bottom,
+                PointN([1, 0, 0]) if positive => Face::East,
+                PointN([1, 0, 0]) => Face::West,
+                _ if positive => Face::South,
+                _ => Face::North,
+            };
+            let voxel_mat = blocks.texture_layer(Voxel(*material), face) as f32;
 
             vert_vox_mat_vals.extend_from_slice(&[voxel_mat, voxel_mat, voxel_mat, voxel_mat]);
         }
@@ -587,14 +1259,113 @@ fn process_quad_buffer(
     }
 }
 
-fn generate_mesh(voxel_map: &VoxelMap, extent: Extent3i) -> Option<ChunkMeshData> {
+/// Copies a chunk and a one voxel border around it out of the map, so it can be meshed on another
+/// thread.
+fn copy_padded_chunk(voxel_map: &VoxelMap, extent: Extent3i) -> Array3<Voxel> {
     let extent_padded = extent.padded(1);
     let mut map = Array3::fill(extent_padded, Voxel(0));
     copy_extent(&extent_padded, voxel_map, &mut map);
+
+    map
+}
+
+fn copy_chunk(voxel_map: &VoxelMap, extent: Extent3i) -> Array3<Voxel> {
+    let mut map = Array3::fill(extent, Voxel::AIR);
+    copy_extent(&extent, voxel_map, &mut map);
+
+    map
+}
+
+fn generate_mesh(
+    map: Array3<Voxel>,
+    extent_padded: Extent3i,
+    blocks: &BlockRegistry,
+) -> ChunkMeshes {
+    // Transparent voxels are air to the opaque mesh, so opaque faces next to them are kept.
+    let mut opaque = map.clone();
+    // Whether every block in the chunk is solid exactly when it is opaque, so the opaque mesh
+    // can double as the collider.
+    let mut collider_matches_opaque = true;
+    opaque.for_each_mut(&extent_padded, |_p: Point3i, v: &mut Voxel| {
+        if *v != Voxel::AIR && blocks.is_solid(*v) == blocks.is_transparent(*v) {
+            collider_matches_opaque = false;
+        }
+        if blocks.is_transparent(*v) {
+            *v = Voxel::AIR;
+        }
+    });
+
+    // Each transparent type is meshed on its own, with everything else as air. Faces between two
+    // voxels of the same type are hidden, while faces against opaque voxels or other transparent
+    // types are kept.
+    let mut translucent: Option<ChunkMeshData> = None;
+    for transparent in blocks.transparent_voxels() {
+        let mut only_type = map.clone();
+        only_type.for_each_mut(&extent_padded, |_p: Point3i, v: &mut Voxel| {
+            if *v != transparent {
+                *v = Voxel::AIR;
+            }
+        });
+        if let Some(mesh_data) = mesh_voxels(&only_type, extent_padded, blocks) {
+            match translucent.as_mut() {
+                Some(translucent) => translucent.append(mesh_data),
+                None => translucent = Some(mesh_data),
+            }
+        }
+    }
+
+    let opaque = mesh_voxels(&opaque, extent_padded, blocks);
+    // Otherwise the collider gets its own mesh of just the solid blocks, e.g. for solid glass or
+    // opaque blocks that can be walked through.
+    let collider = if collider_matches_opaque {
+        opaque.as_ref().map(build_collider)
+    } else {
+        let mut solid = map;
+        solid.for_each_mut(&extent_padded, |_p: Point3i, v: &mut Voxel| {
+            if !blocks.is_solid(*v) {
+                *v = Voxel::AIR;
+            }
+        });
+        mesh_voxels(&solid, extent_padded, blocks).map(|mesh_data| build_collider(&mesh_data))
+    };
+
+    ChunkMeshes {
+        opaque,
+        translucent,
+        collider,
+    }
+}
+
+fn build_collider(mesh_data: &ChunkMeshData) -> Collider {
+    let vertices = mesh_data
+        .pos_norm_tex_mesh
+        .positions
+        .iter()
+        .map(|p| bevy_rapier3d::rapier::math::Point::from_slice(p))
+        .collect();
+    let indices: Vec<u32> = mesh_data
+        .pos_norm_tex_mesh
+        .indices
+        .iter()
+        .map(|i| *i as u32)
+        .collect();
+    let indices = indices
+        .chunks(3)
+        .map(|i| bevy_rapier3d::rapier::na::Point3::<u32>::from_slice(i))
+        .collect();
+
+    ColliderBuilder::trimesh(vertices, indices).build()
+}
+
+fn mesh_voxels(
+    map: &Array3<Voxel>,
+    extent_padded: Extent3i,
+    blocks: &BlockRegistry,
+) -> Option<ChunkMeshData> {
     let mut quads = GreedyQuadsBuffer::new(extent_padded);
-    greedy_quads(&map, &extent_padded, &mut quads);
+    greedy_quads(map, &extent_padded, &mut quads);
 
-    process_quad_buffer(quads, &map, &extent_padded)
+    process_quad_buffer(quads, map, &extent_padded, blocks)
 }
 
 
@@ -603,17 +1374,20 @@ fn create_chunk_entity(
     meshes: &mut ResMut<Assets<Mesh>>,
     mut bodies: &mut ResMut<RigidBodySet>,
     colliders: &mut ResMut<ColliderSet>,
-    mesh_data: Option<ChunkMeshData>,
-    voxel_material: Handle<TerrainMaterial>,
-    pipelines: &RenderPipelines,
+    opaque: Option<ChunkMeshData>,
+    collider: Option<Collider>,
+    material: &ChunkMaterial,
 ) -> Option<ChunkEntityMesh> {
-    
-    if let Some(mesh_data) = mesh_data {
+    if opaque.is_none() && collider.is_none() {
+        return None;
+    }
+
+    let mesh = opaque.map(|mesh_data| {
         let mut render_mesh = Mesh::new(PrimitiveTopology::TriangleList);
         
         render_mesh.set_attribute(
             Mesh::ATTRIBUTE_POSITION,
-            VertexAttributeValues::Float3(mesh_data.pos_norm_tex_mesh.positions.clone()),
+            VertexAttributeValues::Float3(mesh_data.pos_norm_tex_mesh.positions),
         );
         render_mesh.set_attribute(
             Mesh::ATTRIBUTE_NORMAL,
@@ -640,45 +1414,34 @@ fn create_chunk_entity(
             .map(|i| *i as u32)
             .collect();
     
-        render_mesh.set_indices(Some(Indices::U32(indices.clone())));
-    
-        let mesh = meshes.add(render_mesh);
+        render_mesh.set_indices(Some(Indices::U32(indices)));
     
-        let vertices = mesh_data
-            .pos_norm_tex_mesh
-            .positions
-            .iter()
-            .map(|p| bevy_rapier3d::rapier::math::Point::from_slice(p))
-            .collect();
-        let indices = indices
-            .chunks(3)
-            .map(|i| bevy_rapier3d::rapier::na::Point3::<u32>::from_slice(i))
-            .collect();
-    
-        let body_handle = bodies.insert(RigidBodyBuilder::new_static().build());
-        let collider_handle = colliders.insert(
-            ColliderBuilder::trimesh(vertices, indices).build(),
-            body_handle,
-            &mut bodies,
-        );
-    
-        let entity = commands
+        meshes.add(render_mesh)
+    });
+
+    match &mesh {
+        Some(mesh) => commands
             .spawn(MeshBundle {
                 mesh: mesh.clone(),
-                render_pipelines: pipelines.to_owned(),
+                render_pipelines: material.pipelines.clone(),
                 ..Default::default()
             })
-            .with(voxel_material)
-            .with_bundle((
-                RigidBodyHandleComponent::from(body_handle),
-                ColliderHandleComponent::from(collider_handle),
-            ))
-            .current_entity()
-            .unwrap();
-        Some((entity, mesh, body_handle))
-    } else {
-        None
-    }
+            .with(material.material.clone()),
+        None => commands.spawn(()),
+    };
+
+    let body_handle = collider.map(|collider| {
+        let body_handle = bodies.insert(RigidBodyBuilder::new_static().build());
+        let collider_handle = colliders.insert(collider, body_handle, &mut bodies);
+        commands.with_bundle((
+            RigidBodyHandleComponent::from(body_handle),
+            ColliderHandleComponent::from(collider_handle),
+        ));
+        body_handle
+    });
+
+    let entity = commands.current_entity().unwrap();
+    Some((entity, mesh, body_handle))
 }
 
 fn generate_chunk_meshes_system(
@@ -687,68 +1450,444 @@ fn generate_chunk_meshes_system(
     mut bodies: ResMut<RigidBodySet>,
     mut colliders: ResMut<ColliderSet>,
     mut joints: ResMut<JointSet>,
-    voxels: ChangedRes<GeneratedVoxelResource>,
+    mut voxels: ResMut<GeneratedVoxelResource>,
     mut voxel_meshes: ResMut<GeneratedMeshesResource>,
-    query: Query<&Transform, With<GenerateAtTag>>,
+    mut tasks: ResMut<ChunkTasks>,
+    scheduler: Res<ChunkScheduler>,
+    task_pool: Res<AsyncComputeTaskPool>,
     assets: ResMut<VoxelAssetHandles>,
+    blocks: Res<BlockRegistryResource>,
 ) {
-    let cam_transform = query.iter().next().expect("Failed to get camera transform");
-    let cam_pos = cam_transform.translation;
-    let cam_pos = PointN([cam_pos.x.round() as i32, 0i32, cam_pos.z.round() as i32]);
+    let opaque_material = ChunkMaterial::new(&assets.material, &assets.pipeline);
+    let translucent_material =
+        ChunkMaterial::new(&assets.translucent_material, &assets.translucent_pipeline);
 
-    let pipelines =
-        RenderPipelines::from_pipelines(vec![RenderPipeline::new(assets.pipeline.clone())]);
+    // Jobs for chunks that were unloaded since they started are dropped, which cancels them.
+    tasks.meshing.retain(|p, _| voxels.is_loaded(p));
 
-    let view_distance = voxels.view_distance;
-    let chunk_size = voxels.chunk_size;
-    let extent = transform_to_extent(cam_pos, view_distance);
-    let extent = extent_modulo_expand(extent, chunk_size);
-    let min = extent.minimum;
-    let max = extent.least_upper_bound();
+    let chunk_shape = voxels.chunk_shape();
+    let copy_for_meshing = |p: Point3i, voxels: &GeneratedVoxelResource| {
+        let chunk_extent = Extent3i::from_min_and_shape(p, chunk_shape);
+        (copy_padded_chunk(&voxels.map, chunk_extent), chunk_extent.padded(1))
+    };
 
-    let max_height = voxels.max_height;
-    let vd2 = view_distance * view_distance;
-    let mut to_remove: HashSet<Point3i> = voxel_meshes.generated_map.keys().cloned().collect();
-    for z in (min.z()..max.z()).step_by(chunk_size as usize) {
-        for x in (min.x()..max.x()).step_by(chunk_size as usize) {
-            let p = PointN([x, 0, z]);
-            let d = p - cam_pos;
-            if d.dot(&d) > vd2 {
-                continue;
-            }
-            to_remove.remove(&p);
-            if voxel_meshes.generated_map.get(&p).is_some() {
-                continue;
-            }
+    // Edited chunks are meshed right here, outside the upload budget, so edits show up on the
+    // next frame instead of waiting behind generation jobs. A job started from their old voxels
+    // is dropped, which cancels it.
+    let mut finished = Vec::new();
+    let dirty: Vec<Point3i> = voxel_meshes.dirty_chunks.drain().collect();
+    for p in dirty.into_iter().filter(|p| voxels.is_loaded(p)) {
+        tasks.meshing.remove(&p);
+        voxel_meshes.stale_chunks.remove(&p);
+        let (padded_chunk, extent_padded) = copy_for_meshing(p, &voxels);
+        finished.push((p, generate_mesh(padded_chunk, extent_padded, &blocks.0)));
+    }
 
-            let builder = ChunkMapBuilder {
-                chunk_shape: PointN([CHUNK_SIZE; 3]),
-                ambient_value: Voxel(0),
-                default_chunk_metadata: (),
-            };
+    // Upload the most urgent finished meshes first. The rest wait for a later frame. Chunks that
+    // aren't in view come last.
+    let mut pending: Vec<Point3i> = tasks.meshing.keys().cloned().collect();
+    pending.sort_by_key(|p| scheduler.rank(p).unwrap_or(usize::MAX));
+    let mut uploads = 0;
+    for p in pending {
+        if uploads >= tasks.config.mesh_uploads_per_frame {
+            break;
+        }
+        if let Some(task) = tasks.meshing.get_mut(&p) {
+            if let Some(chunk_meshes) = task.now_or_never() {
+                tasks.meshing.remove(&p);
+                finished.push((p, chunk_meshes));
+                uploads += 1;
+            }
+        }
+    }
+    for (p, chunk_meshes) in finished {
 
-            let mesh_data = generate_mesh(
-                &voxels.map,
-                Extent3i::from_min_and_shape(p, PointN([chunk_size, max_height, chunk_size])),
+        // Replace the old entities, if this was a remesh.
+        if let Some(entities) = voxel_meshes.generated_map.remove(&p) {
+            despawn_chunk_entity(
+                &mut commands,
+                &mut meshes,
+                &mut bodies,
+                &mut colliders,
+                &mut joints,
+                entities,
             );
+        }
 
-            let entity_mesh = create_chunk_entity(
+        let opaque = create_chunk_entity(
+            &mut commands,
+            &mut meshes,
+            &mut bodies,
+            &mut colliders,
+            chunk_meshes.opaque,
+            chunk_meshes.collider,
+            &opaque_material,
+        );
+        let translucent = chunk_meshes.translucent.map(|mesh_data| {
+            create_translucent_entity(
+                mesh_data,
+                p,
+                &mut commands,
+                &translucent_material,
+                &mut meshes,
+            )
+        });
+        voxel_meshes.generated_map.insert(
+            p,
+            ChunkEntities {
+                opaque,
+                translucent,
+            },
+        );
+        voxels.chunk_states.insert(p, ChunkState::Meshed);
+    }
+
+    let spawn_meshing = |p: Point3i, voxels: &GeneratedVoxelResource| {
+        // Any edit made after this copy marks the chunk dirty, which replaces this job.
+        let (padded_chunk, extent_padded) = copy_for_meshing(p, voxels);
+        let blocks = blocks.0.clone();
+        task_pool.spawn(async move { generate_mesh(padded_chunk, extent_padded, &blocks) })
+    };
+
+    let max_tasks = tasks.config.max_meshing_tasks;
+    for p in scheduler.in_view() {
+        if tasks.meshing.len() >= max_tasks
+            || !voxels.is_loaded(p)
+            || tasks.meshing.contains_key(p)
+            || (voxel_meshes.generated_map.contains_key(p)
+                && !voxel_meshes.stale_chunks.contains(p))
+        {
+            continue;
+        }
+        voxel_meshes.stale_chunks.remove(p);
+        tasks.meshing.insert(*p, spawn_meshing(*p, &voxels));
+    }
+
+    // Chunks between the view distance and the unload margin keep their entities.
+    let to_remove: Vec<Point3i> = voxel_meshes
+        .generated_map
+        .keys()
+        .filter(|p| !voxels.is_loaded(p))
+        .cloned()
+        .collect();
+    for p in &to_remove {
+        voxel_meshes.dirty_chunks.remove(p);
+        voxel_meshes.stale_chunks.remove(p);
+        if let Some(entities) = voxel_meshes.generated_map.remove(p) {
+            despawn_chunk_entity(
                 &mut commands,
                 &mut meshes,
                 &mut bodies,
                 &mut colliders,
-                mesh_data,
-                assets.material.clone(),
-                &pipelines,
+                &mut joints,
+                entities,
             );
-            voxel_meshes.generated_map.insert(p, entity_mesh);
         }
     }
-    for p in &to_remove {
-        if let Some(Some((entity, mesh, body))) = voxel_meshes.generated_map.remove(p) {
-            commands.despawn(entity);
+}
+
+/// Queues modified chunks that were marked for unloading last frame for saving and drops the
+/// rest from the map, then marks loaded chunks that are out of range of every `GenerateAtTag`
+/// entity. Chunks only count as out of range once they are `unload_margin` past the view
+/// distance, so they don't churn at the border.
+fn unload_chunks_system(
+    mut voxels: ResMut<GeneratedVoxelResource>,
+    mut save_queue: ResMut<SaveQueue>,
+    voxel_meshes: Res<GeneratedMeshesResource>,
+    query: Query<&GlobalTransform, With<GenerateAtTag>>,
+) {
+    let centers: Vec<Point3i> = query
+        .iter()
+        .map(|transform| {
+            PointN([
+                transform.translation.x.round() as i32,
+                transform.translation.y.round() as i32,
+                transform.translation.z.round() as i32,
+            ])
+        })
+        .collect();
+    let in_range = |voxels: &GeneratedVoxelResource, p: Point3i| {
+        centers
+            .iter()
+            .any(|center| voxels.is_in_range(p, *center, voxels.unload_margin))
+    };
+
+    let unloading: Vec<(Point3i, ChunkState)> = voxels
+        .chunk_states
+        .iter()
+        .filter(|(p, state)| match state {
+            ChunkState::Unloading => !voxel_meshes.generated_map.contains_key(p),
+            ChunkState::Saving => true,
+            _ => false,
+        })
+        .map(|(p, state)| (*p, *state))
+        .collect();
+    for (p, state) in unloading {
+        // The player came back before it was dropped. A chunk that is still being saved stays
+        // in the map, so it can be picked up again as is.
+        if in_range(&voxels, p) {
+            voxels.reload_chunk(p);
+            continue;
+        }
+        if state == ChunkState::Saving {
+            continue;
+        }
+        if voxels.modified_chunks.remove(&p) {
+            save_queue.chunks.push(voxels.chunk_save(p));
+            voxels.chunk_states.insert(p, ChunkState::Saving);
+        } else if save_queue.is_saving(&p) {
+            // An autosave of the chunk hasn't finished; it is dropped once that is done.
+            voxels.chunk_states.insert(p, ChunkState::Saving);
+        } else {
+            voxels.drop_chunk(p);
+        }
+    }
+
+    let out_of_range: Vec<Point3i> = voxels
+        .chunk_states
+        .keys()
+        .filter(|p| voxels.is_loaded(p) && !in_range(&voxels, **p))
+        .cloned()
+        .collect();
+    for p in out_of_range {
+        voxels.chunk_states.insert(p, ChunkState::Unloading);
+    }
+
+    // Structure voxels for chunks well past the unload range would pile up as the player moves.
+    // The chunks that placed them are unloaded too, and send them again when they come back.
+    let margin = voxels.unload_margin + 2 * voxels.chunk_size;
+    let mut pending_writes = std::mem::take(&mut voxels.pending_writes);
+    pending_writes.retain(|p| {
+        centers
+            .iter()
+            .any(|center| voxels.is_in_range(*p, *center, margin))
+    });
+    voxels.pending_writes = pending_writes;
+}
+
+/// Finishes the background save, queues every modified chunk, the player's position and the edit
+/// history when the autosave timer fires, and starts the next save on the IO task pool. Chunks
+/// that were saved because they were unloading are dropped from the map once their save is done.
+/// Chunks that failed to save stay in the map and are marked modified again.
+fn save_chunks_system(
+    time: Res<Time>,
+    task_pool: Res<IoTaskPool>,
+    mut voxels: ResMut<GeneratedVoxelResource>,
+    mut save_queue: ResMut<SaveQueue>,
+    mut history: ResMut<EditHistory>,
+    tasks: Res<ChunkTasks>,
+    generator: Res<TerrainGeneratorResource>,
+    query: Query<&GlobalTransform, With<GenerateAtTag>>,
+) {
+    let finished = match &mut save_queue.in_flight {
+        Some((_, task)) => task.now_or_never(),
+        None => None,
+    };
+    if let Some(result) = finished {
+        let (keys, _) = save_queue.in_flight.take().unwrap();
+        finish_save(&mut voxels, &mut save_queue, keys, result);
+    }
+
+    let autosave = match &mut save_queue.autosave_timer {
+        Some(timer) => timer.tick(time.delta_seconds()).just_finished(),
+        None => false,
+    };
+    if autosave {
+        let modified: Vec<Point3i> = voxels
+            .modified_chunks
+            .iter()
+            .filter(|p| voxels.is_loaded(p))
+            .cloned()
+            .collect();
+        for p in modified {
+            voxels.modified_chunks.remove(&p);
+            save_queue.chunks.push(voxels.chunk_save(p));
+        }
+        save_queue.player = query.iter().next().map(|transform| PlayerState {
+            position: transform.translation.into(),
+        });
+        if let Some(saved) = history.take_unsaved() {
+            save_queue.history = Some(saved);
+        }
+    }
+
+    if save_queue.in_flight.is_some()
+        || (save_queue.chunks.is_empty()
+            && save_queue.player.is_none()
+            && save_queue.history.is_none())
+    {
+        return;
+    }
+
+    let chunks = std::mem::take(&mut save_queue.chunks);
+    let player = save_queue.player.take();
+    let saved_history = save_queue.history.take();
+    let keys = chunks.iter().map(|chunk| chunk.key).collect();
+    let generator = generator.0.clone();
+    let config = tasks.config.clone();
+    let storage = tasks.storage.clone();
+    let task = task_pool.spawn(async move {
+        run_save_job(chunks, player, saved_history, &*generator, &config, &storage)
+    });
+    save_queue.in_flight = Some((keys, task));
+}
+
+/// Records a finished save of the chunks at `keys`. Chunks that were saved because they were
+/// unloading are dropped from the map, unless another save of them is queued. Chunks that failed
+/// to save are marked modified again if they are still in the map.
+fn finish_save(
+    voxels: &mut GeneratedVoxelResource,
+    save_queue: &mut SaveQueue,
+    keys: Vec<Point3i>,
+    (saved, errors): SaveResult,
+) {
+    let mut saved_keys = HashSet::new();
+    for (p, info) in saved {
+        voxels.save_stats.record(info);
+        let (region, _) = region_key_for(p, voxels.chunk_size);
+        save_queue.written_regions.insert(region);
+        saved_keys.insert(p);
+    }
+    for err in errors {
+        warn!("Failed to save world: {}", err);
+    }
+
+    for p in keys {
+        if !voxels.has_voxels(&p) {
+            continue;
+        }
+        let state = voxels.chunk_states[&p];
+        if saved_keys.contains(&p) {
+            if state == ChunkState::Saving && !save_queue.is_saving(&p) {
+                voxels.drop_chunk(p);
+            }
+        } else {
+            // Keep the edits around to be saved again: an unloading chunk goes back to loaded,
+            // so it is queued again if it is still out of range.
+            voxels.modified_chunks.insert(p);
+            if state == ChunkState::Saving {
+                voxels.chunk_states.insert(p, ChunkState::Loaded);
+            }
+        }
+    }
+}
+
+/// Encodes and writes `chunks`, then `player` and `history`. For delta saves the chunk is
+/// generated again to diff against, which is why this runs off the main thread.
+fn run_save_job(
+    chunks: Vec<ChunkSave>,
+    player: Option<PlayerState>,
+    history: Option<SavedHistory>,
+    generator: &dyn TerrainGenerator,
+    config: &TerrainConfig,
+    storage: &WorldStorage,
+) -> SaveResult {
+    let chunk_size = config.chunk_size;
+    let chunks: Vec<ChunkSave> = chunks
+        .into_iter()
+        .map(|mut chunk| {
+            if config.save_mode == SaveMode::Delta {
+                let chunk_extent = *chunk.voxels.extent();
+                chunk.baseline = Some(generate_baseline(generator, config, chunk_extent).voxels);
+            }
+            chunk
+        })
+        .collect();
+
+    let _lock = storage.lock();
+    let (saved, mut errors) = save_chunks(&storage.dir, chunks, chunk_size);
+    if let Some(player) = player {
+        if let Err(err) = player.save(&storage.dir) {
+            errors.push(err);
+        }
+    }
+    if let Some(history) = history {
+        if let Err(err) = history.save(&storage.dir) {
+            errors.push(err);
+        }
+    }
+
+    (saved, errors)
+}
+
+/// Waits for the background save, then writes every modified chunk that is still loaded, the
+/// player's position and the edit history when the app is closing, reports how much space the
+/// session's saves took, and compacts the region files written this session.
+fn save_on_exit_system(
+    mut exit_reader: Local<EventReader<AppExit>>,
+    exit_events: Res<Events<AppExit>>,
+    mut voxels: ResMut<GeneratedVoxelResource>,
+    mut save_queue: ResMut<SaveQueue>,
+    mut history: ResMut<EditHistory>,
+    tasks: Res<ChunkTasks>,
+    generator: Res<TerrainGeneratorResource>,
+    query: Query<&GlobalTransform, With<GenerateAtTag>>,
+) {
+    if exit_reader.iter(&exit_events).next().is_none() {
+        return;
+    }
+
+    // Chunks that fail here are marked modified again and get another try below.
+    if let Some((keys, task)) = save_queue.in_flight.take() {
+        let result = futures::executor::block_on(task);
+        finish_save(&mut voxels, &mut save_queue, keys, result);
+    }
+
+    let mut chunks = std::mem::take(&mut save_queue.chunks);
+    let modified: Vec<Point3i> = voxels.modified_chunks.drain().collect();
+    // A chunk that isn't in the map would read as air.
+    for p in modified.into_iter().filter(|p| voxels.has_voxels(p)) {
+        chunks.push(voxels.chunk_save(p));
+    }
+    let player = query.iter().next().map(|transform| PlayerState {
+        position: transform.translation.into(),
+    });
+    history.end_batch();
+    let saved_history = history.take_unsaved().or_else(|| save_queue.history.take());
+    let (saved, errors) = run_save_job(
+        chunks,
+        player,
+        saved_history,
+        &*generator.0,
+        &tasks.config,
+        &tasks.storage,
+    );
+    for (p, info) in saved {
+        voxels.save_stats.record(info);
+        let (region, _) = region_key_for(p, voxels.chunk_size);
+        save_queue.written_regions.insert(region);
+    }
+    for err in errors {
+        warn!("Failed to save world: {}", err);
+    }
+    info!("World {}", voxels.save_stats);
+
+    let _lock = tasks.storage.lock();
+    let regions = save_queue.written_regions.drain();
+    for (path, err) in compact_regions(&tasks.storage.dir, voxels.chunk_size, regions) {
+        warn!("Failed to compact {}: {}", path.display(), err);
+    }
+}
+
+fn despawn_chunk_entity(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    bodies: &mut RigidBodySet,
+    colliders: &mut ColliderSet,
+    joints: &mut JointSet,
+    entities: ChunkEntities,
+) {
+    if let Some((entity, mesh, body)) = entities.opaque {
+        commands.despawn(entity);
+        if let Some(mesh) = mesh {
             meshes.remove(&mesh);
-            bodies.remove(body, &mut *colliders, &mut *joints);
         }
+        if let Some(body) = body {
+            bodies.remove(body, colliders, joints);
+        }
+    }
+    if let Some((entity, mesh)) = entities.translucent {
+        commands.despawn(entity);
+        meshes.remove(&mesh);
     }
 }
@@ -0,0 +1,206 @@
+use super::{
+    generator::Voxel,
+    save_load::{write_atomic, WorldStorageError},
+};
+use building_blocks::core::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, fmt, fs, path::Path};
+
+const HISTORY_FILE: &str = "history.dat";
+
+/// One voxel changed by an edit.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct VoxelChange {
+    pub position: [i32; 3],
+    pub before: Voxel,
+    pub after: Voxel,
+}
+
+impl VoxelChange {
+    pub fn point(&self) -> Point3i {
+        PointN(self.position)
+    }
+}
+
+/// Everything changed by one edit, or by the edits between `VoxelWorld::begin_batch` and
+/// `VoxelWorld::end_batch`. It is undone and redone as a whole.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EditBatch {
+    pub changes: Vec<VoxelChange>,
+}
+
+/// Edits made through `VoxelWorld` that can be undone, and undone edits that can be redone. It
+/// is saved with the world, so edits to chunks that were unloaded or saved in an earlier session
+/// can still be undone once those chunks are loaded again.
+#[derive(Default)]
+pub struct EditHistory {
+    /// Most batches kept for undo. The oldest are dropped past this. 0 turns history off.
+    depth: usize,
+    undo: VecDeque<EditBatch>,
+    redo: Vec<EditBatch>,
+    /// The batch being built between `begin_batch` and `end_batch`.
+    open: Option<EditBatch>,
+    /// Whether anything changed since the history was last saved.
+    changed: bool,
+}
+
+/// What gets written to `history.dat`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedHistory {
+    undo: Vec<EditBatch>,
+    redo: Vec<EditBatch>,
+}
+
+#[derive(Debug)]
+pub enum EditHistoryError {
+    NothingToUndo,
+    NothingToRedo,
+    /// The batch touches a chunk that isn't loaded. It stays in the history.
+    ChunkNotLoaded(Point3i),
+}
+
+impl fmt::Display for EditHistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NothingToUndo => write!(f, "nothing to undo"),
+            Self::NothingToRedo => write!(f, "nothing to redo"),
+            Self::ChunkNotLoaded(key) => write!(f, "chunk {:?} is not loaded", key),
+        }
+    }
+}
+
+impl std::error::Error for EditHistoryError {}
+
+impl EditHistory {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the world's saved history. Batches past `depth` are dropped.
+    pub fn load(world_dir: &Path, depth: usize) -> Result<Self, WorldStorageError> {
+        let mut history = Self::new(depth);
+        let path = world_dir.join(HISTORY_FILE);
+        if !path.exists() {
+            return Ok(history);
+        }
+
+        let bytes = fs::read(path)?;
+        let saved: SavedHistory =
+            bincode::deserialize(&bytes).map_err(WorldStorageError::HistoryDecode)?;
+        history.undo = saved.undo.into();
+        history.redo = saved.redo;
+        history.trim();
+
+        Ok(history)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.open = None;
+        self.changed = true;
+    }
+
+    /// A copy of the history to save, or `None` if it hasn't changed since the last one.
+    pub fn take_unsaved(&mut self) -> Option<SavedHistory> {
+        if !self.changed {
+            return None;
+        }
+
+        self.changed = false;
+        Some(SavedHistory {
+            undo: self.undo.iter().cloned().collect(),
+            redo: self.redo.clone(),
+        })
+    }
+
+    pub(crate) fn begin_batch(&mut self) {
+        self.end_batch();
+        self.open = Some(EditBatch::default());
+    }
+
+    pub(crate) fn end_batch(&mut self) {
+        if let Some(batch) = self.open.take() {
+            self.push(batch);
+        }
+    }
+
+    /// Records changes made by an edit, in the open batch if there is one.
+    pub(crate) fn record(&mut self, changes: Vec<VoxelChange>) {
+        if self.depth == 0 || changes.is_empty() {
+            return;
+        }
+
+        match &mut self.open {
+            Some(batch) => batch.changes.extend(changes),
+            None => self.push(EditBatch { changes }),
+        }
+    }
+
+    /// The batch to undo next. Any open batch is closed first.
+    pub(crate) fn peek_undo(&mut self) -> Option<&EditBatch> {
+        self.end_batch();
+        self.undo.back()
+    }
+
+    pub(crate) fn peek_redo(&mut self) -> Option<&EditBatch> {
+        self.end_batch();
+        self.redo.last()
+    }
+
+    /// Moves the last batch from the undo stack to the redo stack, after it was undone.
+    pub(crate) fn finish_undo(&mut self) {
+        if let Some(batch) = self.undo.pop_back() {
+            self.redo.push(batch);
+            self.changed = true;
+        }
+    }
+
+    pub(crate) fn finish_redo(&mut self) {
+        if let Some(batch) = self.redo.pop() {
+            self.undo.push_back(batch);
+            self.trim();
+            self.changed = true;
+        }
+    }
+
+    fn push(&mut self, batch: EditBatch) {
+        if batch.changes.is_empty() {
+            return;
+        }
+
+        // A new edit makes the undone ones unreachable.
+        self.redo.clear();
+        self.undo.push_back(batch);
+        self.trim();
+        self.changed = true;
+    }
+
+    fn trim(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+        // The next batch to redo is last, so drop from the front.
+        let excess = self.redo.len().saturating_sub(self.depth);
+        self.redo.drain(..excess);
+    }
+}
+
+impl SavedHistory {
+    pub fn save(&self, world_dir: &Path) -> Result<(), WorldStorageError> {
+        fs::create_dir_all(world_dir)?;
+        let bytes = bincode::serialize(self).map_err(WorldStorageError::HistoryEncode)?;
+        write_atomic(&world_dir.join(HISTORY_FILE), &bytes)
+    }
+}
@@ -0,0 +1,85 @@
+use super::{
+    config::TerrainConfig,
+    migrations::CHUNK_FORMAT_VERSION,
+    save_load::{write_atomic, WorldStorageError},
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+const LEVEL_FILE: &str = "level.dat";
+const PLAYER_FILE: &str = "player.ron";
+
+/// World-wide settings, saved next to the region files. A world keeps the terrain it was
+/// created with, even if the config file changes later.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LevelData {
+    /// The newest chunk format the world has been opened with.
+    pub format_version: u32,
+    pub seed: u32,
+    /// `TerrainGenerator::id` of the generator that created the world.
+    pub generator: String,
+    pub config: TerrainConfig,
+}
+
+impl LevelData {
+    pub fn new(config: &TerrainConfig, generator: &str) -> Self {
+        Self {
+            format_version: CHUNK_FORMAT_VERSION,
+            seed: config.seed,
+            generator: generator.to_string(),
+            config: config.clone(),
+        }
+    }
+
+    /// Reads the world's `level.dat`, or `None` if the world is new.
+    pub fn load(world_dir: &Path) -> Result<Option<Self>, WorldStorageError> {
+        let path = world_dir.join(LEVEL_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)?;
+        let level: Self = ron::de::from_str(&contents).map_err(WorldStorageError::LevelDecode)?;
+        if level.format_version > CHUNK_FORMAT_VERSION {
+            return Err(WorldStorageError::UnsupportedChunkFormat(
+                level.format_version,
+            ));
+        }
+
+        Ok(Some(level))
+    }
+
+    pub fn save(&self, world_dir: &Path) -> Result<(), WorldStorageError> {
+        fs::create_dir_all(world_dir)?;
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(WorldStorageError::LevelEncode)?;
+        write_atomic(&world_dir.join(LEVEL_FILE), contents.as_bytes())
+    }
+}
+
+/// Where the player was when the world was last saved.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub position: [f32; 3],
+}
+
+impl PlayerState {
+    /// Reads the saved player state, or `None` if there isn't one yet.
+    pub fn load(world_dir: &Path) -> Result<Option<Self>, WorldStorageError> {
+        let path = world_dir.join(PLAYER_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)?;
+        let state = ron::de::from_str(&contents).map_err(WorldStorageError::LevelDecode)?;
+        Ok(Some(state))
+    }
+
+    pub fn save(&self, world_dir: &Path) -> Result<(), WorldStorageError> {
+        fs::create_dir_all(world_dir)?;
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(WorldStorageError::LevelEncode)?;
+        write_atomic(&world_dir.join(PLAYER_FILE), contents.as_bytes())
+    }
+}
@@ -0,0 +1,42 @@
+use super::save_load::{wrap_full_chunk, WorldStorageError};
+
+/// Version of the chunk encoding written by `save_chunk_to_file`.
+///
+/// 1. Bincode `SerializableChunkMap` of `u8` voxels, compressed with Lz4.
+/// 2. Bincode `ChunkRecord`, which holds either a format 1 chunk or voxel deltas, and which
+///    neighbours' structures are written into the chunk.
+///
+/// When the encoding changes, bump this and add a migration from the previous version to
+/// `CHUNK_MIGRATIONS`. Chunks are upgraded one version at a time as they are loaded.
+pub const CHUNK_FORMAT_VERSION: u32 = 2;
+
+/// Turns a chunk encoded with format `from` into format `from + 1`.
+pub struct ChunkMigration {
+    pub from: u32,
+    pub migrate: fn(&[u8]) -> Result<Vec<u8>, WorldStorageError>,
+}
+
+pub const CHUNK_MIGRATIONS: &[ChunkMigration] = &[ChunkMigration {
+    from: 1,
+    migrate: wrap_full_chunk,
+}];
+
+/// Upgrades `bytes`, encoded with chunk format `format`, to `CHUNK_FORMAT_VERSION`.
+pub fn migrate_chunk(format: u32, bytes: Vec<u8>) -> Result<Vec<u8>, WorldStorageError> {
+    if format > CHUNK_FORMAT_VERSION {
+        return Err(WorldStorageError::UnsupportedChunkFormat(format));
+    }
+
+    let mut format = format;
+    let mut bytes = bytes;
+    while format < CHUNK_FORMAT_VERSION {
+        let migration = CHUNK_MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == format)
+            .ok_or(WorldStorageError::UnsupportedChunkFormat(format))?;
+        bytes = (migration.migrate)(&bytes)?;
+        format += 1;
+    }
+
+    Ok(bytes)
+}
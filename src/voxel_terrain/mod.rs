@@ -1,11 +1,19 @@
+pub mod biome;
+pub mod blocks;
+pub mod caves;
+pub mod config;
+pub mod edit;
 pub mod generator;
+pub mod history;
+pub mod level;
+pub mod migrations;
+pub mod ores;
+pub mod raycast;
+mod region;
 mod save_load;
-
-pub mod constants {
-    pub const CHUNK_SIZE: i32 = 16;
-    pub const MAX_CHUNK_HEIGHT: i32 = 16;
-    pub const SEA_LEVEL: f64 = 50.0;
-    pub const TERRAIN_Y_SCALE: f64 = 1.0;
-    pub const NUM_TEXTURE_LAYERS: u32 = 5;
-    pub const VIEW_DISTANCE: i32 = 192;
-}
+pub mod scheduler;
+mod seed;
+pub mod structures;
+pub mod terrain_generator;
+pub mod water;
+pub mod worlds;
@@ -0,0 +1,172 @@
+use super::{generator::Voxel, seed::chunk_rng};
+use building_blocks::{core::prelude::*, storage::prelude::*};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How one kind of ore is distributed through the stone.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OreConfig {
+    pub voxel: Voxel,
+    /// Veins stay between these heights, inclusive.
+    pub min_y: i32,
+    pub max_y: i32,
+    /// Number of voxels a vein tries to place.
+    pub vein_size: u32,
+    /// Average number of veins per chunk. The fractional part is a chance of one more vein.
+    pub veins_per_chunk: f32,
+}
+
+pub fn default_ores() -> Vec<OreConfig> {
+    vec![
+        OreConfig {
+            voxel: Voxel::COAL_ORE,
+            min_y: 5,
+            max_y: 80,
+            vein_size: 12,
+            veins_per_chunk: 8.0,
+        },
+        OreConfig {
+            voxel: Voxel::IRON_ORE,
+            min_y: 5,
+            max_y: 50,
+            vein_size: 6,
+            veins_per_chunk: 4.0,
+        },
+        OreConfig {
+            voxel: Voxel::GOLD_ORE,
+            min_y: 5,
+            max_y: 25,
+            vein_size: 4,
+            veins_per_chunk: 0.5,
+        },
+    ]
+}
+
+/// Decoration pass that runs after the base terrain is generated. Veins are random walks that
+/// only replace stone inside the chunk, so the result depends on nothing but the seed and the
+/// chunk.
+pub fn place_ores(voxels: &mut Array3<Voxel>, extent: Extent3i, seed: u32, ores: &[OreConfig]) {
+    let min = extent.minimum;
+    let max = extent.least_upper_bound();
+
+    for (i, ore) in ores.iter().enumerate() {
+        let min_y = ore.min_y.max(min.y());
+        let max_y = ore.max_y.min(max.y() - 1);
+        if min_y > max_y {
+            continue;
+        }
+
+        // Give every ore its own stream, so adding an ore to the list doesn't move the others.
+        let mut rng = chunk_rng(seed.wrapping_add(i as u32), min);
+        let mut num_veins = ore.veins_per_chunk.trunc() as u32;
+        if rng.gen::<f32>() < ore.veins_per_chunk.fract() {
+            num_veins += 1;
+        }
+
+        for _ in 0..num_veins {
+            let mut p = PointN([
+                rng.gen_range(min.x(), max.x()),
+                rng.gen_range(min_y, max_y + 1),
+                rng.gen_range(min.z(), max.z()),
+            ]);
+            for _ in 0..ore.vein_size {
+                if extent.contains(&p) && p.y() >= min_y && p.y() <= max_y {
+                    let v = voxels.get_mut(&p);
+                    if *v == Voxel::STONE {
+                        *v = ore.voxel;
+                    }
+                }
+                p = p + PointN([
+                    rng.gen_range(-1, 2),
+                    rng.gen_range(-1, 2),
+                    rng.gen_range(-1, 2),
+                ]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stone_chunk() -> (Array3<Voxel>, Extent3i) {
+        let extent = Extent3i::from_min_and_shape(PointN([-16, 0, 32]), PointN([16, 96, 16]));
+        (Array3::fill(extent, Voxel::STONE), extent)
+    }
+
+    fn place(seed: u32) -> (Array3<Voxel>, Extent3i) {
+        let (mut voxels, extent) = stone_chunk();
+        place_ores(&mut voxels, extent, seed, &default_ores());
+        (voxels, extent)
+    }
+
+    fn points(extent: Extent3i) -> Vec<Point3i> {
+        let min = extent.minimum;
+        let max = extent.least_upper_bound();
+        let mut points = Vec::new();
+        for z in min.z()..max.z() {
+            for y in min.y()..max.y() {
+                for x in min.x()..max.x() {
+                    points.push(PointN([x, y, z]));
+                }
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn ores_stay_within_their_counts_and_heights() {
+        let (voxels, extent) = place(1234);
+        for ore in default_ores().iter() {
+            let placed: Vec<Point3i> = points(extent)
+                .into_iter()
+                .filter(|p| voxels.get(p) == ore.voxel)
+                .collect();
+            let max_count = ore.vein_size * ore.veins_per_chunk.ceil() as u32;
+            assert!(
+                placed.len() as u32 <= max_count,
+                "{:?}: {} placed, at most {} expected",
+                ore.voxel,
+                placed.len(),
+                max_count
+            );
+            for p in placed {
+                assert!(
+                    p.y() >= ore.min_y && p.y() <= ore.max_y,
+                    "{:?} at {:?}",
+                    ore.voxel,
+                    p
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_ore_is_placed() {
+        // On its own, so no other ore can take the stone at the start of a vein, and with at
+        // least one vein, so the first voxel of it is always placed.
+        for ore in default_ores().iter() {
+            let ore = OreConfig {
+                veins_per_chunk: ore.veins_per_chunk.max(1.0),
+                ..ore.clone()
+            };
+            let (mut voxels, extent) = stone_chunk();
+            place_ores(&mut voxels, extent, 1234, &[ore.clone()]);
+            let placed = points(extent)
+                .into_iter()
+                .filter(|p| voxels.get(p) == ore.voxel)
+                .count();
+            assert!(placed > 0, "{:?}: none placed", ore.voxel);
+        }
+    }
+
+    #[test]
+    fn same_seed_places_the_same_ores() {
+        let (first, extent) = place(1234);
+        let (second, _) = place(1234);
+        for p in points(extent) {
+            assert_eq!(first.get(&p), second.get(&p), "at {:?}", p);
+        }
+    }
+}
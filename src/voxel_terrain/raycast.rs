@@ -0,0 +1,199 @@
+use super::generator::{Voxel, VoxelMap};
+use bevy::prelude::*;
+use building_blocks::{core::prelude::*, storage::prelude::*};
+
+/// Where a ray first hit a voxel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RaycastHit {
+    /// The voxel that was hit. Voxel `p` fills the unit cube from `p` to `p + 1`.
+    pub position: Point3i,
+    /// Normal of the face the ray entered through, pointing back towards the ray's origin. This
+    /// is zero if the ray started inside the voxel.
+    pub normal: Point3i,
+    /// Distance along the ray to the hit face.
+    pub distance: f32,
+}
+
+impl RaycastHit {
+    /// The voxel on the other side of the hit face, where a block placed on it would go.
+    pub fn adjacent(&self) -> Point3i {
+        self.position + self.normal
+    }
+}
+
+/// The voxel the player's camera is looking at, if one is in reach. Kept up to date by the
+/// player controller.
+#[derive(Default)]
+pub struct TargetedBlock(pub Option<RaycastHit>);
+
+/// Walks the voxels along a ray with a DDA (Amanatides and Woo) and returns the first one for
+/// which `is_hit` is true, up to `max_distance` from `origin`. Voxels in chunks that aren't
+/// loaded read as air.
+pub fn raycast<F>(
+    map: &VoxelMap,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    is_hit: F,
+) -> Option<RaycastHit>
+where
+    F: Fn(Voxel) -> bool,
+{
+    if direction.length_squared() == 0.0 {
+        return None;
+    }
+    let origin: [f32; 3] = origin.into();
+    let direction: [f32; 3] = direction.normalize().into();
+
+    let mut voxel = [0; 3];
+    let mut step = [0; 3];
+    // Distance along the ray to the next voxel boundary on each axis, and between boundaries.
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+    for axis in 0..3 {
+        voxel[axis] = origin[axis].floor() as i32;
+        if direction[axis] > 0.0 {
+            step[axis] = 1;
+            t_max[axis] = (voxel[axis] as f32 + 1.0 - origin[axis]) / direction[axis];
+            t_delta[axis] = 1.0 / direction[axis];
+        } else if direction[axis] < 0.0 {
+            step[axis] = -1;
+            t_max[axis] = (voxel[axis] as f32 - origin[axis]) / direction[axis];
+            t_delta[axis] = -1.0 / direction[axis];
+        }
+    }
+
+    let mut normal = [0; 3];
+    let mut distance = 0.0;
+    loop {
+        if is_hit(map.get(&PointN(voxel))) {
+            return Some(RaycastHit {
+                position: PointN(voxel),
+                normal: PointN(normal),
+                distance,
+            });
+        }
+
+        let axis = if t_max[0] < t_max[1] {
+            if t_max[0] < t_max[2] {
+                0
+            } else {
+                2
+            }
+        } else if t_max[1] < t_max[2] {
+            1
+        } else {
+            2
+        };
+        distance = t_max[axis];
+        if distance > max_distance {
+            return None;
+        }
+
+        voxel[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+        normal = [0; 3];
+        normal[axis] = -step[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTER: [f32; 3] = [8.5, 8.5, 8.5];
+
+    /// One 16³ chunk at the origin, air except for `solid`.
+    fn map_with(solid: &[Point3i]) -> VoxelMap {
+        let chunk_shape = PointN([16; 3]);
+        let builder = ChunkMapBuilder {
+            chunk_shape,
+            ambient_value: Voxel::AIR,
+            default_chunk_metadata: (),
+        };
+        let mut map = builder.build_with_hash_map_storage();
+        let extent = Extent3i::from_min_and_shape(PointN([0; 3]), chunk_shape);
+        let mut voxels = Array3::fill(extent, Voxel::AIR);
+        for p in solid.iter() {
+            *voxels.get_mut(p) = Voxel::STONE;
+        }
+        map.write_chunk(PointN([0; 3]), Chunk::with_array(voxels));
+
+        map
+    }
+
+    fn cast(
+        map: &VoxelMap,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<RaycastHit> {
+        raycast(map, origin, direction, max_distance, |voxel| voxel != Voxel::AIR)
+    }
+
+    #[test]
+    fn hits_along_each_axis_with_the_entered_face() {
+        for axis in 0..3 {
+            for sign in [1, -1].iter() {
+                let mut step = [0; 3];
+                step[axis] = *sign;
+                let step = PointN(step);
+                let solid = PointN([8, 8, 8]) + step + step + step + step;
+                let map = map_with(&[solid]);
+                let mut direction = [0.0; 3];
+                direction[axis] = *sign as f32;
+
+                let hit = cast(&map, CENTER.into(), direction.into(), 10.0).expect("Ray missed");
+                assert_eq!(hit.position, solid);
+                assert_eq!(hit.normal, PointN([0; 3]) - step);
+                assert!((hit.distance - 3.5).abs() < 1e-5, "{}", hit.distance);
+                assert_eq!(hit.adjacent(), solid - step);
+            }
+        }
+    }
+
+    #[test]
+    fn diagonal_ray_hits_the_face_it_crosses() {
+        // A wall at x = 5. The ray crosses x = 5 at y = 2.75, z = 1.625.
+        let mut wall = Vec::new();
+        for z in 0..16 {
+            for y in 0..16 {
+                wall.push(PointN([5, y, z]));
+            }
+        }
+        let map = map_with(&wall);
+        let direction = Vec3::new(2.0, 1.0, 0.5);
+
+        let hit = cast(&map, Vec3::new(0.5, 0.5, 0.5), direction, 20.0).expect("Ray missed");
+        assert_eq!(hit.position, PointN([5, 2, 1]));
+        assert_eq!(hit.normal, PointN([-1, 0, 0]));
+        let expected = 2.25 * direction.length();
+        assert!((hit.distance - expected).abs() < 1e-4, "{}", hit.distance);
+    }
+
+    #[test]
+    fn origin_inside_solid_hits_at_zero_distance() {
+        let map = map_with(&[PointN([8, 8, 8])]);
+
+        let hit = cast(&map, CENTER.into(), Vec3::unit_x(), 10.0).expect("Ray missed");
+        assert_eq!(hit.position, PointN([8, 8, 8]));
+        assert_eq!(hit.normal, PointN([0, 0, 0]));
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn misses_past_max_distance() {
+        let map = map_with(&[PointN([12, 8, 8])]);
+
+        assert_eq!(cast(&map, CENTER.into(), Vec3::unit_x(), 3.0), None);
+        assert!(cast(&map, CENTER.into(), Vec3::unit_x(), 3.5).is_some());
+        assert_eq!(cast(&map, CENTER.into(), -Vec3::unit_x(), 100.0), None);
+    }
+
+    #[test]
+    fn zero_direction_misses() {
+        let map = map_with(&[PointN([8, 8, 8])]);
+
+        assert_eq!(cast(&map, CENTER.into(), Vec3::zero(), 10.0), None);
+    }
+}
@@ -0,0 +1,544 @@
+//! Region files group the chunks of a 32x32 column of chunk keys on one chunk layer into one
+//! file.
+//!
+//! The file is split into 4 KiB sectors. The first `HEADER_SECTORS` hold the header: a magic
+//! number, the format version, the chunk size, then an offset table and a timestamp table with
+//! one entry per chunk. Each chunk takes a run of whole sectors, starting with its length in
+//! bytes, a checksum and the chunk format version it was encoded with. Rewritten chunks go in
+//! the first free run of sectors that is big enough, so the file only grows when it has to.
+//! Writes never touch sectors the header on disk points at, and only take effect when `commit`
+//! writes the header, so a crash mid-save leaves the file as it was. `compact` squeezes out the
+//! holes.
+
+use super::save_load::{write_atomic, WorldStorageError};
+use building_blocks::core::prelude::*;
+use fnv::FnvHasher;
+use std::{
+    fs::{self, File, OpenOptions},
+    hash::Hasher,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const REGION_SIZE: i32 = 32;
+const CHUNKS_PER_REGION: usize = (REGION_SIZE * REGION_SIZE) as usize;
+
+const SECTOR_SIZE: u64 = 4096;
+const MAGIC: [u8; 4] = *b"BBRG";
+pub const VERSION: u32 = 1;
+
+/// Length, checksum and chunk format in front of every chunk.
+const CHUNK_PREFIX_LEN: u64 = 16;
+
+const OFFSETS_START: u64 = 12;
+const TIMESTAMPS_START: u64 = OFFSETS_START + 8 * CHUNKS_PER_REGION as u64;
+const HEADER_LEN: u64 = TIMESTAMPS_START + 8 * CHUNKS_PER_REGION as u64;
+const HEADER_SECTORS: u32 = ((HEADER_LEN + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32;
+
+/// Where a chunk lives in the file, in sectors. A count of 0 means the chunk isn't stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct SectorRange {
+    start: u32,
+    count: u32,
+}
+
+impl SectorRange {
+    fn end(&self) -> u32 {
+        self.start + self.count
+    }
+}
+
+/// The region a chunk belongs to, and the chunk's index inside it.
+pub fn region_key_for(chunk_key: Point3i, chunk_size: i32) -> (Point3i, usize) {
+    let x = chunk_key.x().div_euclid(chunk_size);
+    let z = chunk_key.z().div_euclid(chunk_size);
+    let region = PointN([
+        x.div_euclid(REGION_SIZE),
+        chunk_key.y().div_euclid(chunk_size),
+        z.div_euclid(REGION_SIZE),
+    ]);
+    let index = (z.rem_euclid(REGION_SIZE) * REGION_SIZE + x.rem_euclid(REGION_SIZE)) as usize;
+
+    (region, index)
+}
+
+pub fn region_file_name(region: Point3i) -> String {
+    format!("r.{}.{}.{}.region", region.x(), region.y(), region.z())
+}
+
+pub struct RegionFile {
+    path: PathBuf,
+    file: File,
+    chunk_size: i32,
+    offsets: Vec<SectorRange>,
+    timestamps: Vec<u64>,
+    /// Chunks whose offset entry points outside the file. They read as corrupt until they are
+    /// written or removed; the rest of the file is still usable.
+    unreadable: Vec<bool>,
+    /// Which sectors are taken, including the header.
+    used: Vec<bool>,
+    /// Sectors given up since the last commit. The header on disk may still point at them, so
+    /// they are only reused once the new header is in.
+    freed: Vec<SectorRange>,
+}
+
+impl RegionFile {
+    /// Opens a region file, creating it with an empty header if it doesn't exist. A commit that
+    /// was cut off is finished first.
+    pub fn open<P: AsRef<Path>>(path: P, chunk_size: i32) -> Result<Self, WorldStorageError> {
+        let path = path.as_ref();
+        if !path.exists() || fs::metadata(path)?.len() == 0 {
+            let offsets = vec![SectorRange::default(); CHUNKS_PER_REGION];
+            let timestamps = vec![0; CHUNKS_PER_REGION];
+            write_atomic(path, &encode_header(chunk_size, &offsets, &timestamps))?;
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        replay_journal(path, &mut file)?;
+
+        let mut header = vec![0; HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if header[0..4] != MAGIC {
+            return Err(WorldStorageError::NotARegionFile);
+        }
+        let version = read_u32(&header, 4);
+        if version != VERSION {
+            return Err(WorldStorageError::VersionMismatch {
+                found: version,
+                expected: VERSION,
+            });
+        }
+        let file_chunk_size = read_u32(&header, 8) as i32;
+        if file_chunk_size != chunk_size {
+            return Err(WorldStorageError::ChunkSizeMismatch {
+                found: file_chunk_size,
+                expected: chunk_size,
+            });
+        }
+
+        let mut offsets: Vec<SectorRange> = (0..CHUNKS_PER_REGION)
+            .map(|i| {
+                let entry = OFFSETS_START as usize + 8 * i;
+                SectorRange {
+                    start: read_u32(&header, entry),
+                    count: read_u32(&header, entry + 4),
+                }
+            })
+            .collect();
+        let timestamps = (0..CHUNKS_PER_REGION)
+            .map(|i| read_u64(&header, TIMESTAMPS_START as usize + 8 * i))
+            .collect();
+
+        let num_sectors = (file.metadata()?.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        let mut used = vec![false; (num_sectors as usize).max(HEADER_SECTORS as usize)];
+        for sector in used.iter_mut().take(HEADER_SECTORS as usize) {
+            *sector = true;
+        }
+        let mut unreadable = vec![false; CHUNKS_PER_REGION];
+        for (index, range) in offsets.iter_mut().enumerate() {
+            if range.count == 0 {
+                continue;
+            }
+            if range.start < HEADER_SECTORS || range.end() as usize > used.len() {
+                // Forget the entry, so its sectors are never freed or read.
+                unreadable[index] = true;
+                *range = SectorRange::default();
+                continue;
+            }
+            for sector in &mut used[range.start as usize..range.end() as usize] {
+                *sector = true;
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            chunk_size,
+            offsets,
+            timestamps,
+            unreadable,
+            used,
+            freed: Vec::new(),
+        })
+    }
+
+    /// Seconds since the Unix epoch when the chunk was last written, if it is stored.
+    pub fn timestamp(&self, index: usize) -> Option<u64> {
+        if self.offsets[index].count > 0 {
+            Some(self.timestamps[index])
+        } else {
+            None
+        }
+    }
+
+    /// The chunk's format version and bytes, after checking the bytes against their checksum.
+    pub fn read_chunk(
+        &mut self,
+        index: usize,
+    ) -> Result<Option<(u32, Vec<u8>)>, WorldStorageError> {
+        let sectors = match self.read_raw(index)? {
+            Some(sectors) => sectors,
+            None => return Ok(None),
+        };
+
+        let len = read_u32(&sectors, 0) as u64;
+        if len + CHUNK_PREFIX_LEN > sectors.len() as u64 {
+            return Err(WorldStorageError::Corrupt("chunk is longer than its sectors"));
+        }
+        let stored = read_u64(&sectors, 4);
+        let format = read_u32(&sectors, 12);
+        let bytes = &sectors[CHUNK_PREFIX_LEN as usize..(CHUNK_PREFIX_LEN + len) as usize];
+        let computed = checksum(bytes);
+        if stored != computed {
+            return Err(WorldStorageError::ChecksumMismatch { stored, computed });
+        }
+
+        Ok(Some((format, bytes.to_vec())))
+    }
+
+    /// Every sector the chunk takes, prefix and padding included, without checking anything.
+    /// Fails if the sectors run past the end of the file.
+    pub fn read_raw(&mut self, index: usize) -> Result<Option<Vec<u8>>, WorldStorageError> {
+        if self.unreadable[index] {
+            return Err(WorldStorageError::Corrupt("offset table points outside the file"));
+        }
+        let range = self.offsets[index];
+        if range.count == 0 {
+            return Ok(None);
+        }
+
+        self.file.seek(SeekFrom::Start(range.start as u64 * SECTOR_SIZE))?;
+        let mut sectors = vec![0; (range.count as u64 * SECTOR_SIZE) as usize];
+        self.file.read_exact(&mut sectors)?;
+
+        Ok(Some(sectors))
+    }
+
+    /// Writes the chunk to free sectors. It replaces the old copy at the next `commit`, and the
+    /// old copy's sectors can be reused after that.
+    pub fn write_chunk(
+        &mut self,
+        index: usize,
+        format: u32,
+        bytes: &[u8],
+    ) -> Result<(), WorldStorageError> {
+        let count = sectors_for(bytes.len());
+        let start = self.find_free(count);
+        self.file.seek(SeekFrom::Start(start as u64 * SECTOR_SIZE))?;
+        self.file.write_all(&encode_sectors(format, bytes))?;
+        for sector in &mut self.used[start as usize..(start + count) as usize] {
+            *sector = true;
+        }
+
+        let old = self.offsets[index];
+        self.freed.push(old);
+        self.offsets[index] = SectorRange { start, count };
+        self.unreadable[index] = false;
+        self.timestamps[index] = now();
+        Ok(())
+    }
+
+    /// Drops the chunk from the file at the next `commit`.
+    pub fn remove_chunk(&mut self, index: usize) -> Result<(), WorldStorageError> {
+        let old = self.offsets[index];
+        self.freed.push(old);
+        self.offsets[index] = SectorRange::default();
+        self.timestamps[index] = 0;
+        self.unreadable[index] = false;
+        Ok(())
+    }
+
+    /// Makes the writes and removals since the last commit take effect. Chunk data never
+    /// overwrites sectors the header on disk points at, so the file only changes when the header
+    /// does. The new header goes to a journal beside the file before it is written over the old
+    /// one, so if it is cut off, `open` writes it again.
+    pub fn commit(&mut self) -> Result<(), WorldStorageError> {
+        self.file.sync_all()?;
+
+        let header = encode_header(self.chunk_size, &self.offsets, &self.timestamps);
+        let journal = journal_path(&self.path);
+        write_atomic(&journal, &header)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.sync_all()?;
+        fs::remove_file(&journal)?;
+
+        for range in std::mem::take(&mut self.freed) {
+            for sector in &mut self.used[range.start as usize..range.end() as usize] {
+                *sector = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every chunk, in index order and with no holes between them, to a new file that
+    /// replaces this one. Uncommitted changes are included. Fails, leaving the file as it was, if
+    /// any chunk can't be read.
+    pub fn compact(mut self) -> Result<Self, WorldStorageError> {
+        let mut offsets = vec![SectorRange::default(); CHUNKS_PER_REGION];
+        let mut chunks = Vec::new();
+        let mut start = HEADER_SECTORS;
+        for (index, offset) in offsets.iter_mut().enumerate() {
+            if let Some((format, bytes)) = self.read_chunk(index)? {
+                let count = sectors_for(bytes.len());
+                *offset = SectorRange { start, count };
+                chunks.extend_from_slice(&encode_sectors(format, &bytes));
+                start += count;
+            }
+        }
+
+        let mut contents = encode_header(self.chunk_size, &offsets, &self.timestamps);
+        contents.extend_from_slice(&chunks);
+        let Self {
+            path,
+            file,
+            chunk_size,
+            ..
+        } = self;
+        // Close the file before it is replaced.
+        drop(file);
+        write_atomic(&path, &contents)?;
+
+        Self::open(&path, chunk_size)
+    }
+
+    /// The first run of `count` free sectors, which may run past the end of the file.
+    fn find_free(&mut self, count: u32) -> u32 {
+        let mut run_start = HEADER_SECTORS;
+        let mut run_len = 0;
+        for sector in HEADER_SECTORS..self.used.len() as u32 {
+            if self.used[sector as usize] {
+                run_start = sector + 1;
+                run_len = 0;
+            } else {
+                run_len += 1;
+                if run_len == count {
+                    return run_start;
+                }
+            }
+        }
+
+        let end = run_start + count;
+        if self.used.len() < end as usize {
+            self.used.resize(end as usize, false);
+        }
+        run_start
+    }
+}
+
+/// Where `commit` keeps the new header until it is written.
+fn journal_path(path: &Path) -> PathBuf {
+    path.with_extension("region.journal")
+}
+
+/// Writes the header left in the journal by a commit that was cut off. The journal is written
+/// atomically, so it is either whole or missing.
+fn replay_journal(path: &Path, file: &mut File) -> Result<(), WorldStorageError> {
+    let journal = journal_path(path);
+    if !journal.exists() {
+        return Ok(());
+    }
+
+    let header = fs::read(&journal)?;
+    if header.len() as u64 == HEADER_SECTORS as u64 * SECTOR_SIZE && header[0..4] == MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+    }
+    fs::remove_file(&journal)?;
+    Ok(())
+}
+
+fn encode_header(chunk_size: i32, offsets: &[SectorRange], timestamps: &[u64]) -> Vec<u8> {
+    let mut header = Vec::with_capacity((HEADER_SECTORS as u64 * SECTOR_SIZE) as usize);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(chunk_size as u32).to_le_bytes());
+    for range in offsets.iter() {
+        header.extend_from_slice(&range.start.to_le_bytes());
+        header.extend_from_slice(&range.count.to_le_bytes());
+    }
+    for timestamp in timestamps.iter() {
+        header.extend_from_slice(&timestamp.to_le_bytes());
+    }
+    header.resize((HEADER_SECTORS as u64 * SECTOR_SIZE) as usize, 0);
+
+    header
+}
+
+/// A chunk's prefix and bytes, padded to whole sectors.
+fn encode_sectors(format: u32, bytes: &[u8]) -> Vec<u8> {
+    let count = sectors_for(bytes.len());
+    let mut data = Vec::with_capacity((count as u64 * SECTOR_SIZE) as usize);
+    data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    data.extend_from_slice(&checksum(bytes).to_le_bytes());
+    data.extend_from_slice(&format.to_le_bytes());
+    data.extend_from_slice(bytes);
+    data.resize((count as u64 * SECTOR_SIZE) as usize, 0);
+
+    data
+}
+
+/// Sectors needed for a chunk of `len` bytes plus its prefix.
+fn sectors_for(len: usize) -> u32 {
+    ((len as u64 + CHUNK_PREFIX_LEN + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(buf)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: i32 = 16;
+
+    fn open(dir: &Path) -> RegionFile {
+        RegionFile::open(dir.join("r.0.0.0.region"), CHUNK_SIZE).unwrap()
+    }
+
+    fn bytes(len: usize, fill: u8) -> Vec<u8> {
+        vec![fill; len]
+    }
+
+    #[test]
+    fn written_chunks_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut region = open(dir.path());
+        region.write_chunk(5, 2, b"hello").unwrap();
+        region.write_chunk(1023, 1, &bytes(5000, 7)).unwrap();
+        region.commit().unwrap();
+        drop(region);
+
+        let mut region = open(dir.path());
+        assert_eq!(region.read_chunk(5).unwrap(), Some((2, b"hello".to_vec())));
+        assert_eq!(region.read_chunk(1023).unwrap(), Some((1, bytes(5000, 7))));
+        assert_eq!(region.read_chunk(6).unwrap(), None);
+        assert!(region.timestamp(5).is_some());
+        assert_eq!(region.timestamp(6), None);
+    }
+
+    #[test]
+    fn uncommitted_writes_are_not_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut region = open(dir.path());
+        region.write_chunk(0, 2, b"hello").unwrap();
+        drop(region);
+
+        assert_eq!(open(dir.path()).read_chunk(0).unwrap(), None);
+    }
+
+    #[test]
+    fn growing_chunk_moves_and_shrinking_chunk_reuses_sectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut region = open(dir.path());
+        region.write_chunk(0, 2, &bytes(100, 1)).unwrap();
+        region.write_chunk(1, 2, &bytes(100, 2)).unwrap();
+        region.commit().unwrap();
+        assert_eq!(region.offsets[0], SectorRange { start: HEADER_SECTORS, count: 1 });
+        assert_eq!(region.offsets[1], SectorRange { start: HEADER_SECTORS + 1, count: 1 });
+
+        // Too big for its old sector, which chunk 1 is right behind.
+        region.write_chunk(0, 2, &bytes(10_000, 3)).unwrap();
+        region.commit().unwrap();
+        assert_eq!(region.offsets[0], SectorRange { start: HEADER_SECTORS + 2, count: 3 });
+        assert_eq!(region.read_chunk(0).unwrap(), Some((2, bytes(10_000, 3))));
+
+        // Fits in the sector it started in, which the commit freed.
+        region.write_chunk(0, 2, &bytes(200, 4)).unwrap();
+        region.commit().unwrap();
+        assert_eq!(region.offsets[0], SectorRange { start: HEADER_SECTORS, count: 1 });
+        drop(region);
+
+        let mut region = open(dir.path());
+        assert_eq!(region.read_chunk(0).unwrap(), Some((2, bytes(200, 4))));
+        assert_eq!(region.read_chunk(1).unwrap(), Some((2, bytes(100, 2))));
+    }
+
+    #[test]
+    fn removed_chunk_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut region = open(dir.path());
+        region.write_chunk(3, 2, b"hello").unwrap();
+        region.write_chunk(4, 2, b"world").unwrap();
+        region.commit().unwrap();
+        region.remove_chunk(3).unwrap();
+        region.commit().unwrap();
+        drop(region);
+
+        let mut region = open(dir.path());
+        assert_eq!(region.read_chunk(3).unwrap(), None);
+        assert_eq!(region.timestamp(3), None);
+        assert_eq!(region.read_chunk(4).unwrap(), Some((2, b"world".to_vec())));
+    }
+
+    #[test]
+    fn compact_truncates_and_keeps_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("r.0.0.0.region");
+        let mut region = open(dir.path());
+        region.write_chunk(0, 2, &bytes(100, 1)).unwrap();
+        region.write_chunk(1, 2, &bytes(10_000, 2)).unwrap();
+        region.write_chunk(2, 2, &bytes(100, 3)).unwrap();
+        region.commit().unwrap();
+        region.remove_chunk(1).unwrap();
+        region.commit().unwrap();
+        let timestamp = region.timestamp(2);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (HEADER_SECTORS as u64 + 5) * SECTOR_SIZE
+        );
+
+        let mut region = region.compact().unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (HEADER_SECTORS as u64 + 2) * SECTOR_SIZE
+        );
+        assert_eq!(region.read_chunk(0).unwrap(), Some((2, bytes(100, 1))));
+        assert_eq!(region.read_chunk(1).unwrap(), None);
+        assert_eq!(region.read_chunk(2).unwrap(), Some((2, bytes(100, 3))));
+        assert_eq!(region.timestamp(2), timestamp);
+    }
+
+    #[test]
+    fn negative_chunk_keys_map_to_the_region_below() {
+        // Chunk (-1, 2, -33) is the last chunk of region (-1, 2, -2) on both axes.
+        let (region, index) = region_key_for(PointN([-16, 32, -33 * 16]), CHUNK_SIZE);
+        assert_eq!(region, PointN([-1, 2, -2]));
+        assert_eq!(index, 31 * 32 + 31);
+
+        // Chunk (-32, -1, 0) is the first chunk of region (-1, -1, 0).
+        let (region, index) = region_key_for(PointN([-32 * 16, -16, 0]), CHUNK_SIZE);
+        assert_eq!(region, PointN([-1, -1, 0]));
+        assert_eq!(index, 0);
+
+        assert_eq!(
+            region_key_for(PointN([0, 0, 0]), CHUNK_SIZE),
+            (PointN([0, 0, 0]), 0)
+        );
+    }
+}
@@ -1,53 +1,506 @@
 use super::{
-    constants::CHUNK_SIZE,
-    generator::{Voxel, VoxelMap},
+    generator::Voxel,
+    migrations::{migrate_chunk, CHUNK_FORMAT_VERSION},
+    region::{region_file_name, region_key_for, RegionFile},
+    structures::StructureSources,
 };
 use building_blocks::core::prelude::*;
 use building_blocks::storage::{compression::Lz4, prelude::*, BincodeCompression};
 use fnv::FnvHashMap;
-use std::io::{prelude::*, BufReader};
-use std::{fs::File, io::Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
 
-const SAVE_DIR: &str = "./map_save";
+/// Where unreadable chunks and region files are moved, so they can be looked at later. Relative
+/// to the world's directory.
+const QUARANTINE_DIR: &str = "quarantine";
 
-pub fn save_chunk_to_file(
-    pos: Point3i,
-    voxel_map: &VoxelMap,
-    extent: Extent3i,
-) -> Result<(), Error> {
-    let file_name = format!("{}/chunk_{}_{}", SAVE_DIR, pos.x(), pos.z());
-    let mut file = File::create(file_name)?;
-    let bytes = serialize_chunk(voxel_map, extent);
-    file.write_all(&bytes)?;
+/// How modified chunks are written.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SaveMode {
+    /// Every voxel of the chunk.
+    Full,
+    /// Only the voxels that differ from what the generator makes for the chunk. On load the chunk
+    /// is generated again and the differences are applied. A chunk that changed so much that the
+    /// full chunk is smaller is still saved in full.
+    Delta,
+}
+
+/// One voxel that differs from the generator's output.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct VoxelDelta {
+    /// Linear index of the voxel in the chunk, x first.
+    pub offset: u32,
+    pub voxel: Voxel,
+}
+
+/// A chunk as stored in a region file, from chunk format 2 on.
+#[derive(Serialize, Deserialize)]
+struct ChunkRecord {
+    structures: StructureSources,
+    voxels: ChunkVoxels,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ChunkVoxels {
+    /// A bincode `SerializableChunkMap`, which is all chunk format 1 had.
+    Full(Vec<u8>),
+    Delta(Vec<VoxelDelta>),
+}
+
+/// A chunk read back from disk.
+pub struct LoadedChunk {
+    pub voxels: LoadedVoxels,
+    /// The neighbours whose structures are written into the voxels.
+    pub structures: StructureSources,
+}
+
+pub enum LoadedVoxels {
+    Full(Array3<Voxel>),
+    /// Apply these to the generator's output with `apply_deltas`.
+    Delta(Vec<VoxelDelta>),
+}
+
+/// A chunk for `save_chunks` to write.
+pub struct ChunkSave {
+    pub key: Point3i,
+    pub voxels: Array3<Voxel>,
+    /// The neighbours whose structures are written into the voxels.
+    pub structures: StructureSources,
+    /// The generator's output for the chunk. With a baseline, only the voxels that differ from
+    /// it are saved, unless the full chunk is smaller.
+    pub baseline: Option<Array3<Voxel>>,
+}
+
+/// What a single save wrote.
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkSaveInfo {
+    pub mode: SaveMode,
+    pub bytes: usize,
+    /// Size the chunk would have taken in full.
+    pub full_bytes: usize,
+}
+
+/// Running totals of what has been saved, to see how much delta saves help.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SaveStats {
+    pub full_chunks: usize,
+    pub delta_chunks: usize,
+    pub bytes: usize,
+    pub full_bytes: usize,
+}
+
+impl SaveStats {
+    pub fn record(&mut self, info: ChunkSaveInfo) {
+        match info.mode {
+            SaveMode::Full => self.full_chunks += 1,
+            SaveMode::Delta => self.delta_chunks += 1,
+        }
+        self.bytes += info.bytes;
+        self.full_bytes += info.full_bytes;
+    }
+}
+
+impl fmt::Display for SaveStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = if self.full_bytes > 0 {
+            100.0 * self.bytes as f64 / self.full_bytes as f64
+        } else {
+            100.0
+        };
+        write!(
+            f,
+            "saved {} full and {} delta chunks in {} bytes, {:.1}% of {} bytes in full",
+            self.full_chunks, self.delta_chunks, self.bytes, percent, self.full_bytes
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum WorldStorageError {
+    Io(io::Error),
+    Encode(bincode::Error),
+    Decode(bincode::Error),
+    NotARegionFile,
+    VersionMismatch { found: u32, expected: u32 },
+    ChunkSizeMismatch { found: i32, expected: i32 },
+    ChecksumMismatch { stored: u64, computed: u64 },
+    Corrupt(&'static str),
+    UnsupportedChunkFormat(u32),
+    LevelEncode(ron::Error),
+    LevelDecode(ron::Error),
+    InvalidWorldName(String),
+    WorldNotFound(String),
+    WorldExists(String),
+    HistoryEncode(bincode::Error),
+    HistoryDecode(bincode::Error),
+}
+
+impl WorldStorageError {
+    /// Whether the data on disk is damaged, as opposed to unreadable by this version or
+    /// inaccessible.
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            Self::Io(err) => err.kind() == ErrorKind::UnexpectedEof,
+            Self::Decode(_)
+            | Self::NotARegionFile
+            | Self::ChecksumMismatch { .. }
+            | Self::Corrupt(_)
+            | Self::HistoryDecode(_) => true,
+            Self::Encode(_)
+            | Self::VersionMismatch { .. }
+            | Self::ChunkSizeMismatch { .. }
+            | Self::UnsupportedChunkFormat(_)
+            | Self::LevelEncode(_)
+            | Self::LevelDecode(_)
+            | Self::InvalidWorldName(_)
+            | Self::WorldNotFound(_)
+            | Self::WorldExists(_)
+            | Self::HistoryEncode(_) => false,
+        }
+    }
+}
+
+impl From<io::Error> for WorldStorageError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl fmt::Display for WorldStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "world storage I/O failed: {}", err),
+            Self::Encode(err) => write!(f, "failed to encode chunk: {}", err),
+            Self::Decode(err) => write!(f, "failed to decode chunk: {}", err),
+            Self::NotARegionFile => write!(f, "not a region file"),
+            Self::VersionMismatch { found, expected } => write!(
+                f,
+                "region file version {} is not supported, expected {}",
+                found, expected
+            ),
+            Self::ChunkSizeMismatch { found, expected } => write!(
+                f,
+                "region file has chunk size {}, expected {}",
+                found, expected
+            ),
+            Self::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "chunk checksum {:016x} does not match stored checksum {:016x}",
+                computed, stored
+            ),
+            Self::Corrupt(reason) => write!(f, "region file is corrupt: {}", reason),
+            Self::UnsupportedChunkFormat(format) => write!(
+                f,
+                "chunk format {} is not supported, expected at most {}",
+                format, CHUNK_FORMAT_VERSION
+            ),
+            Self::LevelEncode(err) => write!(f, "failed to encode level data: {}", err),
+            Self::LevelDecode(err) => write!(f, "failed to decode level data: {}", err),
+            Self::InvalidWorldName(name) => write!(
+                f,
+                "world name {:?} must be letters, digits, spaces, '-' or '_'",
+                name
+            ),
+            Self::WorldNotFound(name) => write!(f, "world {:?} does not exist", name),
+            Self::WorldExists(name) => write!(f, "world {:?} already exists", name),
+            Self::HistoryEncode(err) => write!(f, "failed to encode edit history: {}", err),
+            Self::HistoryDecode(err) => write!(f, "failed to decode edit history: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WorldStorageError {}
+
+fn region_path(world_dir: &Path, pos: Point3i, chunk_size: i32) -> (PathBuf, usize) {
+    let (region, index) = region_key_for(pos, chunk_size);
+    (world_dir.join(region_file_name(region)), index)
+}
+
+/// Encodes and writes a batch of chunks. Each region file takes all of its chunks in one commit,
+/// so a crash mid-save leaves it as it was.
+///
+/// Region files are edited in place, so callers must make sure nothing else touches them while
+/// this runs.
+pub fn save_chunks(
+    world_dir: &Path,
+    chunks: Vec<ChunkSave>,
+    chunk_size: i32,
+) -> (Vec<(Point3i, ChunkSaveInfo)>, Vec<WorldStorageError>) {
+    let mut saved = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut regions: HashMap<PathBuf, Vec<(Point3i, usize, Vec<u8>)>> = HashMap::new();
+    for chunk in chunks {
+        match encode_chunk(&chunk, chunk_size) {
+            Ok((bytes, info)) => {
+                let (path, index) = region_path(world_dir, chunk.key, chunk_size);
+                regions.entry(path).or_default().push((chunk.key, index, bytes));
+                saved.push((chunk.key, info));
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if let Err(err) = fs::create_dir_all(world_dir) {
+        errors.push(err.into());
+        return (Vec::new(), errors);
+    }
+    for (path, writes) in regions {
+        let result = edit_region(&path, chunk_size, |region_file| {
+            for (_, index, bytes) in writes.iter() {
+                region_file.write_chunk(*index, CHUNK_FORMAT_VERSION, bytes)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            saved.retain(|(key, _)| !writes.iter().any(|(failed, _, _)| failed == key));
+            errors.push(err);
+        }
+    }
+
+    (saved, errors)
+}
+
+fn encode_chunk(
+    chunk: &ChunkSave,
+    chunk_size: i32,
+) -> Result<(Vec<u8>, ChunkSaveInfo), WorldStorageError> {
+    let extent = Extent3i::from_min_and_shape(chunk.key, PointN([chunk_size; 3]));
+    let full = serialize_chunk(&chunk.voxels, extent, chunk_size)?;
+    let full = encode_record(&ChunkRecord {
+        structures: chunk.structures,
+        voxels: ChunkVoxels::Full(full),
+    })?;
+    let full_bytes = full.len();
+    let mut info = ChunkSaveInfo {
+        mode: SaveMode::Full,
+        bytes: full_bytes,
+        full_bytes,
+    };
+    let mut bytes = full;
+    if let Some(baseline) = &chunk.baseline {
+        let deltas = diff_chunk(&chunk.voxels, baseline, extent);
+        let delta = encode_record(&ChunkRecord {
+            structures: chunk.structures,
+            voxels: ChunkVoxels::Delta(deltas),
+        })?;
+        if delta.len() < full_bytes {
+            info.mode = SaveMode::Delta;
+            info.bytes = delta.len();
+            bytes = delta;
+        }
+    }
+
+    Ok((bytes, info))
+}
+
+/// Applies `edit` to a region file and commits it.
+fn edit_region<F>(path: &Path, chunk_size: i32, edit: F) -> Result<(), WorldStorageError>
+where
+    F: FnOnce(&mut RegionFile) -> Result<(), WorldStorageError>,
+{
+    let mut region_file = RegionFile::open(path, chunk_size)?;
+    edit(&mut region_file)?;
+    region_file.commit()
+}
+
+/// Writes a whole file through a temporary file, so readers only ever see the old or the new
+/// contents.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), WorldStorageError> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
     Ok(())
 }
 
+/// Reads back a chunk written by `save_chunks`, or `None` if it was never saved. Chunks in an
+/// older format are upgraded; they are written in the current format the next time they are
+/// saved.
 pub fn load_chunk_from_file(
+    world_dir: &Path,
     pos: Point3i,
-    voxel_map: &mut VoxelMap,
     extent: Extent3i,
-) -> Result<(), Error> {
-    let file_name = format!("{}/chunk_{}_{}", SAVE_DIR, pos.x(), pos.z());
-    let file = File::open(file_name)?;
-    let mut buf_reader = BufReader::new(file);
-    let mut buf = Vec::new();
-    buf_reader.read_to_end(&mut buf)?;
-    deserialize_chunk(buf, extent, voxel_map);
-    Ok(())
+) -> Result<Option<LoadedChunk>, WorldStorageError> {
+    let chunk_size = extent.shape.x();
+    let (path, index) = region_path(world_dir, pos, chunk_size);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut region_file = RegionFile::open(path, chunk_size)?;
+    let bytes = match region_file.read_chunk(index)? {
+        Some((CHUNK_FORMAT_VERSION, bytes)) => bytes,
+        Some((format, bytes)) => migrate_chunk(format, bytes)?,
+        None => return Ok(None),
+    };
+    let record: ChunkRecord = bincode::deserialize(&bytes).map_err(WorldStorageError::Decode)?;
+    let voxels = match record.voxels {
+        ChunkVoxels::Full(bytes) => {
+            let mut chunk = Array3::fill(extent, Voxel(0));
+            deserialize_chunk(&bytes, extent, &mut chunk)?;
+            LoadedVoxels::Full(chunk)
+        }
+        ChunkVoxels::Delta(deltas) => LoadedVoxels::Delta(deltas),
+    };
+
+    Ok(Some(LoadedChunk {
+        voxels,
+        structures: record.structures,
+    }))
+}
+
+/// Writes saved voxels over the generator's output for the chunk at `extent`.
+pub fn apply_deltas(voxels: &mut Array3<Voxel>, extent: Extent3i, deltas: &[VoxelDelta]) {
+    let shape = extent.shape;
+    let num_voxels = (shape.x() * shape.y() * shape.z()) as u32;
+    for delta in deltas.iter().filter(|delta| delta.offset < num_voxels) {
+        let offset = delta.offset as i32;
+        let p = extent.minimum
+            + PointN([
+                offset % shape.x(),
+                offset / shape.x() % shape.y(),
+                offset / (shape.x() * shape.y()),
+            ]);
+        *voxels.get_mut(&p) = delta.voxel;
+    }
+}
+
+fn diff_chunk(
+    voxels: &Array3<Voxel>,
+    baseline: &Array3<Voxel>,
+    extent: Extent3i,
+) -> Vec<VoxelDelta> {
+    let min = extent.minimum;
+    let max = extent.least_upper_bound();
+    let mut deltas = Vec::new();
+    let mut offset = 0;
+    for z in min.z()..max.z() {
+        for y in min.y()..max.y() {
+            for x in min.x()..max.x() {
+                let p = PointN([x, y, z]);
+                let voxel = voxels.get(&p);
+                if voxel != baseline.get(&p) {
+                    deltas.push(VoxelDelta { offset, voxel });
+                }
+                offset += 1;
+            }
+        }
+    }
+
+    deltas
+}
+
+fn encode_record(record: &ChunkRecord) -> Result<Vec<u8>, WorldStorageError> {
+    bincode::serialize(record).map_err(WorldStorageError::Encode)
+}
+
+/// Chunk format 1 to 2: wraps the whole chunk in a `ChunkRecord`. Format 1 chunks were saved
+/// before there were structures, so no neighbour has written into them.
+pub fn wrap_full_chunk(bytes: &[u8]) -> Result<Vec<u8>, WorldStorageError> {
+    encode_record(&ChunkRecord {
+        structures: StructureSources::default(),
+        voxels: ChunkVoxels::Full(bytes.to_vec()),
+    })
+}
+
+/// Moves a chunk that failed to load out of its region file and into the quarantine directory,
+/// so it is generated again. If the region file's header can't be read, the whole file is moved.
+pub fn quarantine_chunk(
+    world_dir: &Path,
+    pos: Point3i,
+    chunk_size: i32,
+) -> Result<(), WorldStorageError> {
+    let quarantine_dir = world_dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine_dir)?;
+    let (path, index) = region_path(world_dir, pos, chunk_size);
+
+    if let Err(err) = RegionFile::open(&path, chunk_size) {
+        if err.is_corrupt() {
+            let file_name = path
+                .file_name()
+                .expect("Region path has no file name")
+                .to_string_lossy();
+            fs::rename(&path, quarantine_path(&quarantine_dir, &file_name))?;
+            return Ok(());
+        }
+        return Err(err);
+    }
+
+    edit_region(&path, chunk_size, |region_file| {
+        // The sectors may run past the end of a truncated file, in which case there is nothing
+        // worth keeping.
+        if let Ok(Some(sectors)) = region_file.read_raw(index) {
+            let file_name = format!("chunk_{}_{}_{}.bin", pos.x(), pos.y(), pos.z());
+            fs::write(quarantine_path(&quarantine_dir, &file_name), sectors)?;
+        }
+        region_file.remove_chunk(index)
+    })
+}
+
+/// Where to put `name` in the quarantine directory. A counter is added if an earlier quarantine
+/// already took the name, so nothing quarantined is ever overwritten.
+fn quarantine_path(quarantine_dir: &Path, name: &str) -> PathBuf {
+    let mut path = quarantine_dir.join(name);
+    let mut n = 1;
+    while path.exists() {
+        path = quarantine_dir.join(format!("{}.{}", name, n));
+        n += 1;
+    }
+
+    path
 }
 
-fn serialize_chunk(voxel_map: &VoxelMap, extent: Extent3i) -> Vec<u8> {
-    let _extent_padded = extent.padded(1);
+/// Compacts the files of the given regions, skipping any that don't exist. A region that fails
+/// is left as it was and doesn't stop the rest.
+pub fn compact_regions<I>(
+    world_dir: &Path,
+    chunk_size: i32,
+    regions: I,
+) -> Vec<(PathBuf, WorldStorageError)>
+where
+    I: IntoIterator<Item = Point3i>,
+{
+    let mut errors = Vec::new();
+    for region in regions {
+        let path = world_dir.join(region_file_name(region));
+        if !path.exists() {
+            continue;
+        }
+
+        let result = RegionFile::open(&path, chunk_size)
+            .and_then(|region_file| region_file.compact().map(|_| ()));
+        if let Err(err) = result {
+            errors.push((path, err));
+        }
+    }
+
+    errors
+}
 
+fn serialize_chunk(
+    voxels: &Array3<Voxel>,
+    extent: Extent3i,
+    chunk_size: i32,
+) -> Result<Vec<u8>, WorldStorageError> {
     let builder = ChunkMapBuilder {
-        chunk_shape: PointN([CHUNK_SIZE; 3]),
+        chunk_shape: PointN([chunk_size; 3]),
         ambient_value: Voxel(0),
         default_chunk_metadata: (),
     };
 
     let mut map = builder.build_with_hash_map_storage();
 
-    copy_extent(&extent, voxel_map, &mut map);
+    copy_extent(&extent, voxels, &mut map);
 
     let compression = Lz4 { level: 10 };
     let serializable = futures::executor::block_on(SerializableChunkMap::from_chunk_map(
@@ -55,14 +508,264 @@ fn serialize_chunk(voxel_map: &VoxelMap, extent: Extent3i) -> Vec<u8> {
         map,
     ));
 
-    let serialized: Vec<u8> = bincode::serialize(&serializable).unwrap();
-
-    serialized
+    bincode::serialize(&serializable).map_err(WorldStorageError::Encode)
 }
 
-fn deserialize_chunk(serialized: Vec<u8>, extent: Extent3i, dst_map: &mut VoxelMap) {
+fn deserialize_chunk(
+    serialized: &[u8],
+    extent: Extent3i,
+    dst: &mut Array3<Voxel>,
+) -> Result<(), WorldStorageError> {
     let deserialized: SerializableChunkMap<[i32; 3], Voxel, (), Lz4> =
-        bincode::deserialize(&serialized).unwrap();
+        bincode::deserialize(serialized).map_err(WorldStorageError::Decode)?;
     let map = futures::executor::block_on(deserialized.into_chunk_map(FnvHashMap::default()));
-    copy_extent(&extent, &map, dst_map);
+    copy_extent(&extent, &map, dst);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::region::VERSION;
+    use super::*;
+    use fnv::FnvHasher;
+    use std::hash::Hasher;
+
+    const CHUNK_SIZE: i32 = 16;
+
+    fn chunk_extent() -> Extent3i {
+        Extent3i::from_min_and_shape(PointN([0; 3]), PointN([CHUNK_SIZE; 3]))
+    }
+
+    /// Saves an all-stone chunk at the origin and returns its region file.
+    fn save_stone_chunk(world_dir: &Path) -> PathBuf {
+        let chunk = ChunkSave {
+            key: PointN([0; 3]),
+            voxels: Array3::fill(chunk_extent(), Voxel::STONE),
+            structures: StructureSources::default(),
+            baseline: None,
+        };
+        let (saved, errors) = save_chunks(world_dir, vec![chunk], CHUNK_SIZE);
+        assert_eq!(saved.len(), 1);
+        assert!(errors.is_empty());
+
+        world_dir.join(region_file_name(PointN([0; 3])))
+    }
+
+    fn load(world_dir: &Path) -> Result<Option<LoadedChunk>, WorldStorageError> {
+        load_chunk_from_file(world_dir, PointN([0; 3]), chunk_extent())
+    }
+
+    /// Checks that loading fails as corrupt, and that once the chunk is quarantined, `quarantined`
+    /// exists and the chunk loads as never saved.
+    fn assert_quarantined(world_dir: &Path, quarantined: &str) {
+        let err = load(world_dir).err().expect("Corrupt chunk loaded");
+        assert!(err.is_corrupt(), "{}", err);
+
+        quarantine_chunk(world_dir, PointN([0; 3]), CHUNK_SIZE).unwrap();
+        assert!(world_dir.join(QUARANTINE_DIR).join(quarantined).exists());
+        assert!(matches!(load(world_dir), Ok(None)));
+    }
+
+    /// Byte offset of chunk 0's sectors, read from the region file's offset table.
+    fn first_chunk_start(contents: &[u8]) -> usize {
+        let start = u32::from_le_bytes([contents[12], contents[13], contents[14], contents[15]]);
+        start as usize * 4096
+    }
+
+    #[test]
+    fn saved_chunk_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        save_stone_chunk(dir.path());
+
+        match load(dir.path()) {
+            Ok(Some(LoadedChunk {
+                voxels: LoadedVoxels::Full(voxels),
+                ..
+            })) => {
+                assert_eq!(voxels.get(&PointN([3, 4, 5])), Voxel::STONE)
+            }
+            _ => panic!("Saved chunk didn't load in full"),
+        }
+    }
+
+    #[test]
+    fn truncated_header_quarantines_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = save_stone_chunk(dir.path());
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..100]).unwrap();
+
+        let err = load(dir.path()).err().expect("Truncated file loaded");
+        match &err {
+            WorldStorageError::Io(err) => assert_eq!(err.kind(), ErrorKind::UnexpectedEof),
+            err => panic!("Expected an unexpected EOF, got {}", err),
+        }
+        assert_quarantined(dir.path(), "r.0.0.0.region");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn wrong_magic_quarantines_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = save_stone_chunk(dir.path());
+        let mut contents = fs::read(&path).unwrap();
+        contents[0..4].copy_from_slice(b"NOPE");
+        fs::write(&path, &contents).unwrap();
+
+        assert!(matches!(load(dir.path()), Err(WorldStorageError::NotARegionFile)));
+        assert_quarantined(dir.path(), "r.0.0.0.region");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sectors_past_the_end_quarantine_the_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = save_stone_chunk(dir.path());
+        let mut contents = fs::read(&path).unwrap();
+        contents[12..16].copy_from_slice(&1000u32.to_le_bytes());
+        fs::write(&path, &contents).unwrap();
+
+        assert!(matches!(load(dir.path()), Err(WorldStorageError::Corrupt(_))));
+        // The sectors aren't there to keep, but the rest of the region stays.
+        quarantine_chunk(dir.path(), PointN([0; 3]), CHUNK_SIZE).unwrap();
+        assert!(matches!(load(dir.path()), Ok(None)));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn quarantine_keeps_earlier_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for _ in 0..2 {
+            let path = save_stone_chunk(dir.path());
+            let mut contents = fs::read(&path).unwrap();
+            contents[0..4].copy_from_slice(b"NOPE");
+            fs::write(&path, &contents).unwrap();
+            quarantine_chunk(dir.path(), PointN([0; 3]), CHUNK_SIZE).unwrap();
+        }
+
+        let quarantine_dir = dir.path().join(QUARANTINE_DIR);
+        assert!(quarantine_dir.join("r.0.0.0.region").exists());
+        assert!(quarantine_dir.join("r.0.0.0.region.1").exists());
+    }
+
+    #[test]
+    fn bad_checksum_quarantines_the_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = save_stone_chunk(dir.path());
+        let mut contents = fs::read(&path).unwrap();
+        // The first byte after the chunk's length, checksum and format.
+        let at = first_chunk_start(&contents) + 16;
+        contents[at] ^= 0xFF;
+        fs::write(&path, &contents).unwrap();
+
+        assert!(matches!(
+            load(dir.path()),
+            Err(WorldStorageError::ChecksumMismatch { .. })
+        ));
+        assert_quarantined(dir.path(), "chunk_0_0_0.bin");
+        // Only the chunk is moved; the rest of the region stays.
+        assert!(path.exists());
+    }
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    /// The chunk in the fixtures: stone below y = 8, grass at 8 and air above.
+    fn fixture_voxels() -> Array3<Voxel> {
+        let mut voxels = Array3::fill(chunk_extent(), Voxel::AIR);
+        for z in 0..CHUNK_SIZE {
+            for y in 0..=8 {
+                for x in 0..CHUNK_SIZE {
+                    let voxel = if y < 8 { Voxel::STONE } else { Voxel::GRASS };
+                    *voxels.get_mut(&PointN([x, y, z])) = voxel;
+                }
+            }
+        }
+        voxels
+    }
+
+    fn assert_loads_fixture_chunk(world_dir: &Path, fixture: &str) {
+        let voxels = match load(world_dir) {
+            Ok(Some(LoadedChunk {
+                voxels: LoadedVoxels::Full(voxels),
+                ..
+            })) => voxels,
+            Ok(_) => panic!("{}: chunk didn't load in full", fixture),
+            Err(err) => panic!("{}: {}", fixture, err),
+        };
+        let expected = fixture_voxels();
+        for z in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for x in 0..CHUNK_SIZE {
+                    let p = PointN([x, y, z]);
+                    assert_eq!(voxels.get(&p), expected.get(&p), "{}: at {:?}", fixture, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn old_chunk_format_loads_through_migration() {
+        let fixture = "chunk_format1.region";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(region_file_name(PointN([0; 3])));
+        fs::copy(fixtures_dir().join(fixture), &path).unwrap();
+        let original = fs::read(&path).unwrap();
+
+        assert_loads_fixture_chunk(dir.path(), fixture);
+        assert_eq!(fs::read(&path).unwrap(), original, "Load wrote the file");
+
+        // Saving another chunk into the region keeps the old one as it was.
+        let key = PointN([CHUNK_SIZE, 0, 0]);
+        let extent = Extent3i::from_min_and_shape(key, PointN([CHUNK_SIZE; 3]));
+        let chunk = ChunkSave {
+            key,
+            voxels: Array3::fill(extent, Voxel::STONE),
+            structures: StructureSources::default(),
+            baseline: None,
+        };
+        let (_, errors) = save_chunks(dir.path(), vec![chunk], CHUNK_SIZE);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_loads_fixture_chunk(dir.path(), fixture);
+    }
+
+    /// Writes the fixture with the current encoder. Run with
+    /// `cargo test write_fixtures -- --ignored` if building-blocks changes how it encodes chunks.
+    #[test]
+    #[ignore]
+    fn write_fixtures() {
+        let bytes = serialize_chunk(&fixture_voxels(), chunk_extent(), CHUNK_SIZE).unwrap();
+        let mut hasher = FnvHasher::default();
+        hasher.write(&bytes);
+
+        let mut prefix = (bytes.len() as u32).to_le_bytes().to_vec();
+        prefix.extend_from_slice(&hasher.finish().to_le_bytes());
+        prefix.extend_from_slice(&1u32.to_le_bytes());
+        let region = fixture_region(&prefix, &bytes);
+        fs::write(fixtures_dir().join("chunk_format1.region"), region).unwrap();
+    }
+
+    /// A region file holding one chunk at index 0, laid out by hand so a chunk in an old format
+    /// can be written.
+    fn fixture_region(prefix: &[u8], bytes: &[u8]) -> Vec<u8> {
+        const SECTOR_SIZE: usize = 4096;
+        const HEADER_SECTORS: usize = 5;
+        const CHUNKS_PER_REGION: usize = 1024;
+        let count = (prefix.len() + bytes.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+
+        let mut file = b"BBRG".to_vec();
+        file.extend_from_slice(&VERSION.to_le_bytes());
+        file.extend_from_slice(&(CHUNK_SIZE as u32).to_le_bytes());
+        file.extend_from_slice(&(HEADER_SECTORS as u32).to_le_bytes());
+        file.extend_from_slice(&(count as u32).to_le_bytes());
+        file.resize(12 + 8 * CHUNKS_PER_REGION, 0);
+        file.extend_from_slice(&1_600_000_000u64.to_le_bytes());
+        file.resize(HEADER_SECTORS * SECTOR_SIZE, 0);
+        file.extend_from_slice(prefix);
+        file.extend_from_slice(bytes);
+        file.resize((HEADER_SECTORS + count) * SECTOR_SIZE, 0);
+
+        file
+    }
 }
@@ -0,0 +1,180 @@
+use super::structures::chunk_key_for;
+use building_blocks::core::prelude::*;
+use bevy::math::Vec3;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+/// A `GenerateAtTag` entity, as the scheduler sees it.
+#[derive(Clone, Copy, Debug)]
+pub struct Viewer {
+    /// In voxel coordinates.
+    pub position: Vec3,
+    /// Unit vector the viewer is facing.
+    pub forward: Vec3,
+}
+
+/// Once a viewer turns further than this from the direction the queue was built with (as a
+/// cosine), the queue is rebuilt. About 45 degrees.
+const REBUILD_TURN_COS: f32 = 0.7;
+
+struct QueuedChunk {
+    priority: f32,
+    key: Point3i,
+}
+
+impl PartialEq for QueuedChunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl Eq for QueuedChunk {}
+
+impl PartialOrd for QueuedChunk {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedChunk {
+    // `BinaryHeap` is a max-heap, so the lowest priority value compares greatest.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .partial_cmp(&self.priority)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Decides which chunks to load first. Chunks nearest to any viewer come first, and with a
+/// non-zero `view_direction_bias`, chunks in front of a viewer come before chunks behind it.
+///
+/// The queue is only rebuilt when a viewer moves into another chunk or turns far enough, so the
+/// viewer positions and directions it was built with may lag slightly behind.
+pub struct ChunkScheduler {
+    view_direction_bias: f32,
+    /// Chunk and facing of each viewer when the queue was last built.
+    viewers: Vec<(Point3i, Vec3)>,
+    queue: BinaryHeap<QueuedChunk>,
+    in_view: Vec<Point3i>,
+    /// Index of each chunk in `in_view`.
+    ranks: HashMap<Point3i, usize>,
+    wanted: HashSet<Point3i>,
+}
+
+impl ChunkScheduler {
+    pub fn new(view_direction_bias: f32) -> Self {
+        Self {
+            view_direction_bias,
+            viewers: Vec::new(),
+            queue: BinaryHeap::new(),
+            in_view: Vec::new(),
+            ranks: HashMap::new(),
+            wanted: HashSet::new(),
+        }
+    }
+
+    pub fn needs_rebuild(&self, viewers: &[Viewer], chunk_shape: Point3i) -> bool {
+        if viewers.len() != self.viewers.len() {
+            return true;
+        }
+
+        viewers
+            .iter()
+            .zip(self.viewers.iter())
+            .any(|(viewer, (chunk, forward))| {
+                viewer_chunk(viewer, chunk_shape) != *chunk
+                    || (self.view_direction_bias > 0.0
+                        && viewer.forward.dot(*forward) < REBUILD_TURN_COS)
+            })
+    }
+
+    /// Replaces the queue with `keys`, the chunks in view of any viewer. Returns the chunks that
+    /// were wanted before but no longer are, so their work can be cancelled.
+    pub fn rebuild(
+        &mut self,
+        viewers: &[Viewer],
+        chunk_shape: Point3i,
+        keys: HashSet<Point3i>,
+    ) -> Vec<Point3i> {
+        let stale = self.wanted.difference(&keys).cloned().collect();
+
+        self.viewers = viewers
+            .iter()
+            .map(|viewer| (viewer_chunk(viewer, chunk_shape), viewer.forward))
+            .collect();
+
+        let half_shape = Vec3::new(
+            chunk_shape.x() as f32,
+            chunk_shape.y() as f32,
+            chunk_shape.z() as f32,
+        ) * 0.5;
+        let mut queued: Vec<QueuedChunk> = keys
+            .iter()
+            .map(|key| {
+                let center =
+                    Vec3::new(key.x() as f32, key.y() as f32, key.z() as f32) + half_shape;
+                let priority = viewers
+                    .iter()
+                    .map(|viewer| self.priority(viewer, center))
+                    .fold(f32::INFINITY, f32::min);
+                QueuedChunk {
+                    priority,
+                    key: *key,
+                }
+            })
+            .collect();
+        queued.sort_by(|a, b| b.cmp(a));
+
+        self.in_view = queued.iter().map(|chunk| chunk.key).collect();
+        self.ranks = self
+            .in_view
+            .iter()
+            .enumerate()
+            .map(|(rank, key)| (*key, rank))
+            .collect();
+        self.queue = queued.into();
+        self.wanted = keys;
+
+        stale
+    }
+
+    /// Distance to the viewer, shrunk for chunks in front of it and stretched for chunks behind.
+    fn priority(&self, viewer: &Viewer, center: Vec3) -> f32 {
+        let offset = center - viewer.position;
+        let distance = offset.length();
+        if distance == 0.0 {
+            return 0.0;
+        }
+
+        let facing = offset.dot(viewer.forward) / distance;
+        distance * (1.0 - self.view_direction_bias * facing)
+    }
+
+    /// The most urgent chunk that hasn't been handed out since the last rebuild.
+    pub fn pop(&mut self) -> Option<Point3i> {
+        self.queue.pop().map(|chunk| chunk.key)
+    }
+
+    /// Every chunk in view, most urgent first.
+    pub fn in_view(&self) -> &[Point3i] {
+        &self.in_view
+    }
+
+    /// Where the chunk is in `in_view`, 0 being the most urgent, or `None` if it isn't in view.
+    pub fn rank(&self, key: &Point3i) -> Option<usize> {
+        self.ranks.get(key).cloned()
+    }
+}
+
+fn viewer_chunk(viewer: &Viewer, chunk_shape: Point3i) -> Point3i {
+    let position = PointN([
+        viewer.position.x.round() as i32,
+        viewer.position.y.round() as i32,
+        viewer.position.z.round() as i32,
+    ]);
+
+    chunk_key_for(position, chunk_shape)
+}
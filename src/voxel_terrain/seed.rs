@@ -0,0 +1,76 @@
+use building_blocks::core::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+
+/// Mixes the world seed with a point, so that every chunk (or column, or structure) gets its own
+/// stable stream of random numbers.
+pub fn seed_for_point(world_seed: u32, p: Point3i) -> u64 {
+    let mut hash = splitmix64(world_seed as u64);
+    for coord in [p.x(), p.y(), p.z()].iter() {
+        hash = splitmix64(hash ^ (*coord as u32 as u64));
+    }
+    hash
+}
+
+/// A seeded RNG for the chunk whose minimum corner is `chunk_min`. All generation randomness
+/// should come from here instead of `rand::thread_rng()`.
+pub fn chunk_rng(world_seed: u32, chunk_min: Point3i) -> StdRng {
+    StdRng::seed_from_u64(seed_for_point(world_seed, chunk_min))
+}
+
+fn splitmix64(v: u64) -> u64 {
+    let mut z = v.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        config::TerrainConfig,
+        generator::{generate_chunk, Voxel},
+        structures::StructureWrite,
+        terrain_generator::TerrainGeneratorResource,
+    };
+    use building_blocks::core::prelude::*;
+
+    fn generate(config: &TerrainConfig, min: Point3i) -> (Vec<Voxel>, Vec<StructureWrite>) {
+        let generator = TerrainGeneratorResource::from_config(config);
+        let extent = Extent3i::from_min_and_shape(min, PointN([16, 128, 16]));
+        let chunk = generate_chunk(&*generator.0, config, extent);
+
+        let mut voxels = Vec::new();
+        let max = extent.least_upper_bound();
+        for z in min.z()..max.z() {
+            for y in min.y()..max.y() {
+                for x in min.x()..max.x() {
+                    voxels.push(chunk.voxels.get(&PointN([x, y, z])));
+                }
+            }
+        }
+
+        (voxels, chunk.trees)
+    }
+
+    #[test]
+    fn same_seed_and_chunk_generate_the_same_voxels() {
+        let config = TerrainConfig::default();
+        let min = PointN([32, 0, -48]);
+
+        assert_eq!(generate(&config, min), generate(&config, min));
+    }
+
+    #[test]
+    fn different_seed_or_chunk_generates_different_voxels() {
+        let config = TerrainConfig::default();
+        let min = PointN([32, 0, -48]);
+        let (voxels, _) = generate(&config, min);
+
+        let reseeded = TerrainConfig {
+            seed: config.seed + 1,
+            ..TerrainConfig::default()
+        };
+        assert_ne!(generate(&reseeded, min).0, voxels);
+        assert_ne!(generate(&config, PointN([48, 0, -48])).0, voxels);
+    }
+}
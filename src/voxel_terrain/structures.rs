@@ -0,0 +1,200 @@
+use super::{generator::Voxel, seed::chunk_rng};
+use building_blocks::{core::prelude::*, storage::prelude::*};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TreeConfig {
+    pub enabled: bool,
+    /// Average number of trees per chunk. The fractional part is a chance of one more tree.
+    pub trees_per_chunk: f32,
+    pub min_trunk_height: i32,
+    pub max_trunk_height: i32,
+    pub canopy_radius: i32,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trees_per_chunk: 1.5,
+            min_trunk_height: 4,
+            max_trunk_height: 7,
+            canopy_radius: 2,
+        }
+    }
+}
+
+/// A voxel a structure wants to place. Structure voxels only ever replace air.
+pub type StructureWrite = (Point3i, Voxel);
+
+/// Structure voxels chunks place in their neighbours, by the chunk they fall in and then by the
+/// chunk that placed them. They are kept after they are applied, so a chunk that is dropped and
+/// generated again gets them again.
+#[derive(Default)]
+pub struct PendingWrites {
+    writes: HashMap<Point3i, HashMap<Point3i, Vec<StructureWrite>>>,
+}
+
+impl PendingWrites {
+    /// Sets the voxels `source` places in `chunk_key`, replacing any it placed before.
+    pub fn insert(&mut self, chunk_key: Point3i, source: Point3i, writes: Vec<StructureWrite>) {
+        self.writes.entry(chunk_key).or_default().insert(source, writes);
+    }
+
+    /// Every chunk that places voxels in `chunk_key`, with those voxels.
+    pub fn get(
+        &self,
+        chunk_key: Point3i,
+    ) -> impl Iterator<Item = (&Point3i, &Vec<StructureWrite>)> {
+        self.writes.get(&chunk_key).into_iter().flatten()
+    }
+
+    /// Keeps only the voxels for chunks `keep` returns true for.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Point3i) -> bool) {
+        self.writes.retain(|chunk_key, _| keep(chunk_key));
+    }
+}
+
+/// Which of a chunk's neighbours have written their structures into it. Saved with the chunk, so
+/// a neighbour's structures are never written twice, whichever of the two is generated first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StructureSources(u32);
+
+impl StructureSources {
+    pub fn contains(&self, chunk_key: Point3i, source: Point3i, chunk_shape: Point3i) -> bool {
+        self.0 & neighbour_bit(chunk_key, source, chunk_shape) != 0
+    }
+
+    pub fn insert(&mut self, chunk_key: Point3i, source: Point3i, chunk_shape: Point3i) {
+        self.0 |= neighbour_bit(chunk_key, source, chunk_shape);
+    }
+}
+
+/// One bit for each chunk in the 3x3x3 block around `chunk_key`. Structures never reach further
+/// than that; `TerrainConfig::validate` makes sure trees fit.
+fn neighbour_bit(chunk_key: Point3i, source: Point3i, chunk_shape: Point3i) -> u32 {
+    let d = source - chunk_key;
+    let offset = |axis: usize| d.0[axis].div_euclid(chunk_shape.0[axis]) + 1;
+    debug_assert!(
+        (0..3).all(|axis| (0..3).contains(&offset(axis))),
+        "Chunk {:?} is not next to {:?}",
+        source,
+        chunk_key
+    );
+
+    1 << (offset(0) + 3 * offset(1) + 9 * offset(2))
+}
+
+/// Splits a chunk's structure voxels by the neighbouring chunk they fall in. Voxels inside the
+/// chunk itself are left out.
+pub fn writes_by_chunk(
+    writes: &[StructureWrite],
+    chunk_key: Point3i,
+    chunk_shape: Point3i,
+) -> HashMap<Point3i, Vec<StructureWrite>> {
+    let mut by_chunk: HashMap<Point3i, Vec<StructureWrite>> = HashMap::new();
+    for write in writes.iter() {
+        let key = chunk_key_for(write.0, chunk_shape);
+        if key != chunk_key {
+            by_chunk.entry(key).or_default().push(*write);
+        }
+    }
+
+    by_chunk
+}
+
+/// The minimum of the chunk with shape `chunk_shape` that contains `p`. This is also the chunk's
+/// key in the voxel map.
+pub fn chunk_key_for(p: Point3i, chunk_shape: Point3i) -> Point3i {
+    PointN([
+        p.x().div_euclid(chunk_shape.x()) * chunk_shape.x(),
+        p.y().div_euclid(chunk_shape.y()) * chunk_shape.y(),
+        p.z().div_euclid(chunk_shape.z()) * chunk_shape.z(),
+    ])
+}
+
+/// Writes structure voxels into `voxels`, skipping any that fall outside `extent`.
+pub fn apply_writes(voxels: &mut Array3<Voxel>, extent: Extent3i, writes: &[StructureWrite]) {
+    for (p, voxel) in writes.iter() {
+        if extent.contains(p) {
+            let v = voxels.get_mut(p);
+            if *v == Voxel::AIR {
+                *v = *voxel;
+            }
+        }
+    }
+}
+
+/// Picks tree positions on the grass in this chunk and returns every voxel of every tree. Trees
+/// near the border reach into neighbouring chunks; the caller decides where those writes go.
+/// `voxels` must also hold the layer above `extent`, to tell whether grass on its top layer is
+/// open to the sky.
+pub fn place_trees(
+    voxels: &Array3<Voxel>,
+    extent: Extent3i,
+    seed: u32,
+    config: &TreeConfig,
+) -> Vec<StructureWrite> {
+    let mut writes = Vec::new();
+    if !config.enabled {
+        return writes;
+    }
+
+    let min = extent.minimum;
+    let max = extent.least_upper_bound();
+    // Use a different stream than the ore pass for the same chunk.
+    let mut rng = chunk_rng(seed.wrapping_add(100), min);
+    let mut num_trees = config.trees_per_chunk.trunc() as u32;
+    if rng.gen::<f32>() < config.trees_per_chunk.fract() {
+        num_trees += 1;
+    }
+
+    for _ in 0..num_trees {
+        let x = rng.gen_range(min.x(), max.x());
+        let z = rng.gen_range(min.z(), max.z());
+        let trunk_height = rng.gen_range(config.min_trunk_height, config.max_trunk_height + 1);
+
+        // Find the top of the column. Trees only grow out of grass with air above it.
+        let ground = (min.y()..max.y()).rev().find(|y| {
+            voxels.get(&PointN([x, *y, z])) == Voxel::GRASS
+                && voxels.get(&PointN([x, *y + 1, z])) == Voxel::AIR
+        });
+        let ground = match ground {
+            Some(y) => y,
+            None => continue,
+        };
+
+        add_tree(
+            PointN([x, ground + 1, z]),
+            trunk_height,
+            config.canopy_radius,
+            &mut writes,
+        );
+    }
+
+    writes
+}
+
+fn add_tree(base: Point3i, trunk_height: i32, radius: i32, writes: &mut Vec<StructureWrite>) {
+    for y in 0..trunk_height {
+        writes.push((base + PointN([0, y, 0]), Voxel::WOOD));
+    }
+
+    let top = base.y() + trunk_height;
+    for y in (top - 2)..=(top + 1) {
+        // Narrow the canopy towards the top.
+        let r = if y > top { radius - 1 } else { radius };
+        for z in -r..=r {
+            for x in -r..=r {
+                // Round off the corners.
+                if x.abs() == r && z.abs() == r && r > 1 {
+                    continue;
+                }
+                writes.push((PointN([base.x() + x, y, base.z() + z]), Voxel::LEAVES));
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use super::{
+    biome::{max_surface_depth, surface_voxel, Biome, BiomeMap},
+    caves::CaveCarver,
+    config::{GeneratorKind, TerrainConfig},
+    generator::Voxel,
+    water::WaterCarver,
+};
+use building_blocks::{core::prelude::*, storage::prelude::*};
+use noise::{Fbm, MultiFractal, NoiseFn, RidgedMulti, Seedable};
+use std::sync::Arc;
+
+/// Produces the voxels for one chunk. Every chunk goes through the same pipeline regardless of
+/// which generator fills it, so implementations only need to be deterministic for a given extent.
+pub trait TerrainGenerator: Send + Sync + 'static {
+    fn generate(&self, extent: Extent3i) -> Array3<Voxel>;
+
+    /// Height of the top solid voxel of the column at `x`, `z`, ignoring caves. Water only fills
+    /// air above it. `None` if the generator has no single surface.
+    fn surface_height(&self, _x: i32, _z: i32) -> Option<i32> {
+        None
+    }
+
+    /// Name saved with the world, so a world isn't silently continued with another generator.
+    fn id(&self) -> &str {
+        "custom"
+    }
+}
+
+/// The active terrain generator. Add this resource before `VoxelTerrainGeneratorPlugin` to use
+/// your own generator; otherwise the plugin registers a `HeightmapGenerator` built from the
+/// `TerrainConfig`. It is shared with the background generation tasks.
+pub struct TerrainGeneratorResource(pub Arc<dyn TerrainGenerator>);
+
+impl TerrainGeneratorResource {
+    pub fn new<G: TerrainGenerator>(generator: G) -> Self {
+        Self(Arc::new(generator))
+    }
+
+    /// The built-in generator selected by `config.generator`.
+    pub fn from_config(config: &TerrainConfig) -> Self {
+        match config.generator {
+            GeneratorKind::Heightmap => Self::new(HeightmapGenerator::new(config)),
+            GeneratorKind::Density => Self::new(DensityGenerator::new(config)),
+        }
+    }
+}
+
+/// Ridged multifractal heightmap, shaped and surfaced by biome.
+pub struct HeightmapGenerator {
+    noise: RidgedMulti,
+    biomes: BiomeMap,
+    caves: Option<CaveCarver>,
+    water: WaterCarver,
+    sea_level: f64,
+    terrain_y_scale: f64,
+}
+
+impl HeightmapGenerator {
+    pub fn new(config: &TerrainConfig) -> Self {
+        Self {
+            noise: RidgedMulti::new()
+                .set_seed(config.seed)
+                .set_frequency(config.noise_frequency)
+                .set_octaves(config.noise_octaves),
+            biomes: BiomeMap::new(config.seed, config.biome_frequency),
+            caves: if config.caves.enabled {
+                Some(CaveCarver::new(config.seed, &config.caves))
+            } else {
+                None
+            },
+            water: WaterCarver::new(config.seed, &config.water),
+            sea_level: config.sea_level,
+            terrain_y_scale: config.terrain_y_scale,
+        }
+    }
+
+    /// The biome and surface height of the column at `x`, `z`.
+    pub fn column(&self, x: i32, z: i32) -> (Biome, i32) {
+        let sample = self.biomes.sample(x, z);
+        let yoffset = self.sea_level + sample.height_offset;
+        let yscale = self.terrain_y_scale * self.sea_level * sample.height_scale;
+        let max_y = (self.noise.get([x as f64, z as f64]) * yscale + yoffset).round() as i32;
+        let max_y = self.water.carve_height(x, z, max_y);
+
+        // Anything under water gets a sea floor instead of its biome's usual surface.
+        let biome = if max_y < self.water.water_level() {
+            Biome::Ocean
+        } else {
+            sample.biome
+        };
+
+        (biome, max_y)
+    }
+
+    fn is_cave(&self, p: Point3i) -> bool {
+        self.caves
+            .as_ref()
+            .map_or(false, |caves| caves.is_cave(p.x(), p.y(), p.z()))
+    }
+}
+
+impl TerrainGenerator for HeightmapGenerator {
+    fn generate(&self, extent: Extent3i) -> Array3<Voxel> {
+        let mut voxels = Array3::fill(extent, Voxel::AIR);
+
+        let min = extent.minimum;
+        let shape = extent.shape;
+        let mut columns = Vec::with_capacity((shape.x() * shape.z()) as usize);
+        for z in 0..shape.z() {
+            for x in 0..shape.x() {
+                columns.push(self.column(min.x() + x, min.z() + z));
+            }
+        }
+
+        voxels.for_each_mut(&extent, |p: Point3i, v: &mut Voxel| {
+            let local = p - min;
+            let (biome, max_y) = columns[(local.z() * shape.x() + local.x()) as usize];
+            let y = p.y();
+
+            if y <= max_y && !self.is_cave(p) {
+                *v = surface_voxel(biome, max_y - y);
+            }
+        });
+
+        voxels
+    }
+
+    fn surface_height(&self, x: i32, z: i32) -> Option<i32> {
+        Some(self.column(x, z).1)
+    }
+
+    fn id(&self) -> &str {
+        "heightmap"
+    }
+}
+
+/// 3D density function. The biome heightmap gives a base density that falls off above the
+/// surface, and 3D noise on top of it lets the terrain form overhangs and arches.
+pub struct DensityGenerator {
+    heightmap: HeightmapGenerator,
+    noise: Fbm,
+    squash: f64,
+}
+
+impl DensityGenerator {
+    pub fn new(config: &TerrainConfig) -> Self {
+        Self {
+            heightmap: HeightmapGenerator::new(config),
+            noise: Fbm::new()
+                .set_seed(config.seed.wrapping_add(3))
+                .set_frequency(config.density_frequency)
+                .set_octaves(3),
+            squash: config.density_squash,
+        }
+    }
+
+    fn is_solid(&self, x: i32, y: i32, z: i32, surface_y: i32) -> bool {
+        let base = (surface_y - y) as f64 / self.squash;
+
+        base + self.noise.get([x as f64, y as f64, z as f64]) > 0.0
+    }
+}
+
+impl TerrainGenerator for DensityGenerator {
+    fn generate(&self, extent: Extent3i) -> Array3<Voxel> {
+        let mut voxels = Array3::fill(extent, Voxel::AIR);
+
+        let min = extent.minimum;
+        let max = extent.least_upper_bound();
+        // Surface layers depend on how much solid terrain is above a voxel, so look a little past
+        // the top of the chunk to get the same answer the chunk above would.
+        let lookahead = max_surface_depth();
+        for z in min.z()..max.z() {
+            for x in min.x()..max.x() {
+                let (biome, surface_y) = self.heightmap.column(x, z);
+                let mut depth = 0;
+                for y in (min.y()..max.y() + lookahead).rev() {
+                    if !self.is_solid(x, y, z, surface_y) {
+                        depth = 0;
+                        continue;
+                    }
+                    let p = PointN([x, y, z]);
+                    if y < max.y() && !self.heightmap.is_cave(p) {
+                        *voxels.get_mut(&p) = surface_voxel(biome, depth);
+                    }
+                    depth += 1;
+                }
+            }
+        }
+
+        voxels
+    }
+
+    /// The heightmap surface. 3D noise can raise overhangs above it, and water doesn't reach
+    /// under those.
+    fn surface_height(&self, x: i32, z: i32) -> Option<i32> {
+        Some(self.heightmap.column(x, z).1)
+    }
+
+    fn id(&self) -> &str {
+        "density"
+    }
+}
+
+/// Fills everything at or below `height` with a single voxel type. Handy for test worlds.
+pub struct FlatGenerator {
+    pub height: i32,
+    pub voxel: Voxel,
+}
+
+impl TerrainGenerator for FlatGenerator {
+    fn generate(&self, extent: Extent3i) -> Array3<Voxel> {
+        let mut voxels = Array3::fill(extent, Voxel::AIR);
+        let height = self.height;
+        let voxel = self.voxel;
+        voxels.for_each_mut(&extent, |p: Point3i, v: &mut Voxel| {
+            if p.y() <= height {
+                *v = voxel;
+            }
+        });
+
+        voxels
+    }
+
+    fn surface_height(&self, _x: i32, _z: i32) -> Option<i32> {
+        Some(self.height)
+    }
+
+    fn id(&self) -> &str {
+        "flat"
+    }
+}
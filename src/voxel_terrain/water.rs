@@ -0,0 +1,134 @@
+use super::generator::Voxel;
+use building_blocks::{core::prelude::*, storage::prelude::*};
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, Seedable};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaterConfig {
+    /// Air above the terrain surface and at or below this height is filled with water.
+    pub water_level: i32,
+    pub rivers: bool,
+    pub river_frequency: f64,
+    /// Half-width of a river, in noise units. Rivers follow the zero line of the river noise.
+    pub river_width: f64,
+    /// How far below the water level river beds are cut.
+    pub river_depth: i32,
+    pub lakes: bool,
+    pub lake_frequency: f64,
+    /// Lake noise value above which the ground is pulled down into a lake bed.
+    pub lake_threshold: f64,
+    pub lake_depth: i32,
+}
+
+impl Default for WaterConfig {
+    fn default() -> Self {
+        Self {
+            water_level: 50,
+            rivers: true,
+            river_frequency: 0.003,
+            river_width: 0.04,
+            river_depth: 3,
+            lakes: true,
+            lake_frequency: 0.006,
+            lake_threshold: 0.5,
+            lake_depth: 5,
+        }
+    }
+}
+
+/// Cuts river and lake beds into the heightmap.
+pub struct WaterCarver {
+    river: Perlin,
+    lake: Fbm,
+    config: WaterConfig,
+}
+
+impl WaterCarver {
+    pub fn new(seed: u32, config: &WaterConfig) -> Self {
+        Self {
+            river: Perlin::new().set_seed(seed.wrapping_add(20)),
+            lake: Fbm::new()
+                .set_seed(seed.wrapping_add(21))
+                .set_frequency(config.lake_frequency)
+                .set_octaves(2),
+            config: config.clone(),
+        }
+    }
+
+    pub fn water_level(&self) -> i32 {
+        self.config.water_level
+    }
+
+    /// The surface height of the column at `x`, `z` after rivers and lakes have been cut into
+    /// the terrain height `height`.
+    pub fn carve_height(&self, x: i32, z: i32, height: i32) -> i32 {
+        let mut height = height as f64;
+        let point = [x as f64, z as f64];
+
+        if self.config.rivers {
+            let f = self.config.river_frequency;
+            let r = self.river.get([point[0] * f, point[1] * f]).abs();
+            if r < self.config.river_width {
+                let bed = (self.config.water_level - self.config.river_depth) as f64;
+                // Steeper towards the middle of the river.
+                let t = 1.0 - r / self.config.river_width;
+                height = lower_towards(height, bed, t * t);
+            }
+        }
+
+        if self.config.lakes {
+            let l = self.lake.get(point);
+            if l > self.config.lake_threshold {
+                let bed = (self.config.water_level - self.config.lake_depth) as f64;
+                let t = ((l - self.config.lake_threshold) / (1.0 - self.config.lake_threshold))
+                    .min(1.0);
+                height = lower_towards(height, bed, t.sqrt());
+            }
+        }
+
+        height.round() as i32
+    }
+}
+
+fn lower_towards(height: f64, bed: f64, t: f64) -> f64 {
+    if height <= bed {
+        height
+    } else {
+        height + (bed - height) * t
+    }
+}
+
+/// Fills air at or below `water_level` with water. Only air above the surface of its column is
+/// filled, so caves stay dry. `surface_height` gives the surface of the column at `x`, `z`; where
+/// it returns `None`, every air voxel at or below the water level is filled.
+pub fn fill_water<F>(
+    voxels: &mut Array3<Voxel>,
+    extent: Extent3i,
+    water_level: i32,
+    surface_height: F,
+) where
+    F: Fn(i32, i32) -> Option<i32>,
+{
+    let min = extent.minimum;
+    let shape = extent.shape;
+    if min.y() > water_level {
+        return;
+    }
+
+    let mut columns = Vec::with_capacity((shape.x() * shape.z()) as usize);
+    for z in 0..shape.z() {
+        for x in 0..shape.x() {
+            columns.push(surface_height(min.x() + x, min.z() + z));
+        }
+    }
+
+    voxels.for_each_mut(&extent, |p: Point3i, v: &mut Voxel| {
+        let local = p - min;
+        let above_surface = columns[(local.z() * shape.x() + local.x()) as usize]
+            .map_or(true, |surface| p.y() > surface);
+        if p.y() <= water_level && above_surface && *v == Voxel::AIR {
+            *v = Voxel::WATER;
+        }
+    });
+}
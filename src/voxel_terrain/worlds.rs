@@ -0,0 +1,193 @@
+use super::{config::TerrainConfig, level::LevelData, save_load::WorldStorageError};
+use bevy::prelude::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_SAVES_DIR: &str = "./saves";
+pub const DEFAULT_WORLD_NAME: &str = "world";
+
+/// The world being played. Add this resource before `VoxelTerrainGeneratorPlugin` to pick a
+/// world; otherwise the plugin plays `DEFAULT_WORLD_NAME` under `DEFAULT_SAVES_DIR`. A world
+/// that doesn't exist yet is created with the `TerrainConfig` when the plugin is built.
+#[derive(Clone, Debug)]
+pub struct ActiveWorld {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl Default for ActiveWorld {
+    fn default() -> Self {
+        WorldManager::new(DEFAULT_SAVES_DIR)
+            .world(DEFAULT_WORLD_NAME)
+            .expect("Default world name is invalid")
+    }
+}
+
+/// A world found under the saves root.
+#[derive(Clone, Debug)]
+pub struct WorldSummary {
+    pub name: String,
+    /// `None` if the world's `level.dat` couldn't be read.
+    pub level: Option<LevelData>,
+}
+
+/// Creates, lists, renames, copies and deletes the worlds under a saves root. Each world is a
+/// directory named after it, holding its `level.dat`, player state and region files.
+///
+/// None of these lock the world, so don't rename, copy or delete the world being played.
+pub struct WorldManager {
+    root: PathBuf,
+}
+
+impl WorldManager {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The world called `name`, whether or not it exists yet.
+    pub fn world(&self, name: &str) -> Result<ActiveWorld, WorldStorageError> {
+        Ok(ActiveWorld {
+            name: name.to_string(),
+            dir: self.world_dir(name)?,
+        })
+    }
+
+    pub fn exists(&self, name: &str) -> Result<bool, WorldStorageError> {
+        Ok(self.world_dir(name)?.is_dir())
+    }
+
+    /// Every world under the root, sorted by name. Directories without a `level.dat` are skipped.
+    pub fn list(&self) -> Result<Vec<WorldSummary>, WorldStorageError> {
+        if !self.root.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut worlds = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if path.is_dir() && validate_world_name(name).is_ok() => name,
+                _ => continue,
+            };
+            let level = match LevelData::load(&path) {
+                Ok(Some(level)) => Some(level),
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Failed to read world {:?}: {}", name, err);
+                    None
+                }
+            };
+            worlds.push(WorldSummary {
+                name: name.to_string(),
+                level,
+            });
+        }
+        worlds.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(worlds)
+    }
+
+    /// Creates a new world that generates terrain from `config` with the generator `generator`
+    /// (see `TerrainGenerator::id`).
+    pub fn create(
+        &self,
+        name: &str,
+        config: &TerrainConfig,
+        generator: &str,
+    ) -> Result<ActiveWorld, WorldStorageError> {
+        let world = self.world(name)?;
+        if world.dir.exists() {
+            return Err(WorldStorageError::WorldExists(name.to_string()));
+        }
+
+        LevelData::new(config, generator).save(&world.dir)?;
+        Ok(world)
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), WorldStorageError> {
+        let (from_dir, to_dir) = self.source_and_target(from, to)?;
+        fs::rename(from_dir, to_dir)?;
+        Ok(())
+    }
+
+    /// Copies a world under a new name. The copy keeps the original's seed and settings.
+    pub fn copy(&self, from: &str, to: &str) -> Result<(), WorldStorageError> {
+        let (from_dir, to_dir) = self.source_and_target(from, to)?;
+        if let Err(err) = copy_dir(&from_dir, &to_dir) {
+            // Don't leave half a world behind.
+            let _ = fs::remove_dir_all(&to_dir);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), WorldStorageError> {
+        let dir = self.world_dir(name)?;
+        if !dir.is_dir() {
+            return Err(WorldStorageError::WorldNotFound(name.to_string()));
+        }
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    fn world_dir(&self, name: &str) -> Result<PathBuf, WorldStorageError> {
+        validate_world_name(name)?;
+        Ok(self.root.join(name))
+    }
+
+    fn source_and_target(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<(PathBuf, PathBuf), WorldStorageError> {
+        let from_dir = self.world_dir(from)?;
+        let to_dir = self.world_dir(to)?;
+        if !from_dir.is_dir() {
+            return Err(WorldStorageError::WorldNotFound(from.to_string()));
+        }
+        if to_dir.exists() {
+            return Err(WorldStorageError::WorldExists(to.to_string()));
+        }
+
+        Ok((from_dir, to_dir))
+    }
+}
+
+/// World names become directory names, so they are kept to characters that are safe on every
+/// platform and can't point outside the saves root.
+fn validate_world_name(name: &str) -> Result<(), WorldStorageError> {
+    let valid = !name.is_empty()
+        && name.trim() == name
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(WorldStorageError::InvalidWorldName(name.to_string()))
+    }
+}
+
+/// Copies a directory tree, skipping temporary files left by an interrupted save.
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&path, &target)?;
+        } else if path.extension().and_then(|ext| ext.to_str()) != Some("tmp") {
+            fs::copy(&path, &target)?;
+        }
+    }
+
+    Ok(())
+}